@@ -0,0 +1,1047 @@
+// The audio processing unit. Registers are in 0xff10 - 0xff3f.
+//
+// There are four channels, each producing a digital value between 0 and 15:
+//  1: Square wave with frequency sweep (NR10 - NR14)
+//  2: Square wave (NR21 - NR24)
+//  3: Wave, plays 32 4-bit samples from wave RAM (NR30 - NR34, 0xff30 - 0xff3f)
+//  4: Noise, from a linear feedback shift register (NR41 - NR44)
+//
+// The frame sequencer runs at 512 Hz and clocks the length counters (256 Hz),
+// the sweep (128 Hz) and the volume envelopes (64 Hz).
+//
+// NR50 sets the left/right master volume, NR51 which channels go to which side,
+// and NR52 turns the whole thing on and off.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{
+    error::Error,
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+const CLOCK_SPEED: u32 = 4_194_304;
+const FRAME_SEQUENCER_CYCLES: u32 = CLOCK_SPEED / 512;
+
+// Bits that always read as 1, for 0xff10 - 0xff2f
+const READ_MASK: [u8; 0x20] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10 - NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20 - NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30 - NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40 - NR44
+    0x00, 0x00, 0x70, // NR50 - NR52
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // Unused
+];
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+}
+
+impl LengthCounter {
+    fn load(&mut self, max: u16, length: u16) {
+        self.counter = max - length;
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    // Returns false when the channel should be turned off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.initial_volume = val >> 4;
+        self.increase = val & 0x08 != 0;
+        self.period = val & 0x07;
+    }
+
+    // The DAC is off when the upper 5 bits are all 0
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    enabled: bool,
+    timer: u8,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn write(&mut self, val: u8) {
+        self.period = (val >> 4) & 0x07;
+        self.negate = val & 0x08 != 0;
+        self.shift = val & 0x07;
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+#[derive(Default)]
+struct Square {
+    enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+    // Only used by channel 1
+    sweep: Sweep,
+}
+
+impl Square {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.sweep.write(val),
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(64, (val & 0x3f) as u16);
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | (((val & 0x07) as u16) << 8);
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        self.sweep.shadow_frequency = self.frequency;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        if self.sweep.shift != 0 && self.sweep.next_frequency() > 2047 {
+            self.enabled = false;
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+
+        if self.sweep.enabled && self.sweep.period != 0 {
+            let frequency = self.sweep.next_frequency();
+            if frequency > 2047 {
+                self.enabled = false;
+            } else if self.sweep.shift != 0 {
+                self.frequency = frequency;
+                self.sweep.shadow_frequency = frequency;
+                // The new frequency is checked for overflow again, but not used
+                if self.sweep.next_frequency() > 2047 {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_CYCLES[self.duty as usize][self.duty_position as usize] * self.envelope.volume
+    }
+}
+
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    length: LengthCounter,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(256, val as u16),
+            2 => self.volume_code = (val >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | (((val & 0x07) as u16) << 8);
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger(256);
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        // Two samples per byte, the upper 4 bits are played first
+        let byte = self.ram[(self.position / 2) as usize];
+        let sample = if self.position % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0f
+        };
+        match self.volume_code {
+            0 => 0,
+            code => sample >> (code - 1),
+        }
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    clock_shift: u8,
+    // 7 bit mode, gives a more metallic sound
+    short_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Default for Noise {
+    fn default() -> Noise {
+        Noise {
+            enabled: false,
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            timer: NOISE_DIVISORS[0],
+            lfsr: 0x7fff,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl Noise {
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            1 => self.length.load(64, (val & 0x3f) as u16),
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = val >> 4;
+                self.short_mode = val & 0x08 != 0;
+                self.divisor_code = val & 0x07;
+            }
+            4 => {
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger(64);
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7fff;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 == 1 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+}
+
+/// One of the four sound channels, for muting and capturing them one by one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Square1,
+        Channel::Square2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Channel::Square1 => "square1",
+            Channel::Square2 => "square2",
+            Channel::Wave => "wave",
+            Channel::Noise => "noise",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+
+    /// Accepts the name or the channel number (1 - 4)
+    fn from_str(name: &str) -> Result<Channel, Error> {
+        match name.to_lowercase().as_ref() {
+            "1" | "square1" => Ok(Channel::Square1),
+            "2" | "square2" => Ok(Channel::Square2),
+            "3" | "wave" => Ok(Channel::Wave),
+            "4" | "noise" => Ok(Channel::Noise),
+            _ => Err(Error::UnknownChannel(name.to_string())),
+        }
+    }
+}
+
+pub struct Apu {
+    powered: bool,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    // Raw values written to 0xff10 - 0xff2f, used when reading them back
+    registers: [u8; 0x20],
+    frame_sequencer_step: u8,
+    frame_sequencer_timer: u32,
+    // Samples per second, 0 turns off sample generation
+    sample_rate: u32,
+    // Counts up by sample_rate every cycle, a sample is made every CLOCK_SPEED
+    sample_timer: u64,
+    // Left and right capacitor of the high pass filter
+    capacitor: [f32; 2],
+    capacitor_charge: f32,
+    // Interleaved left/right samples between -1.0 and 1.0
+    samples: Vec<f32>,
+    // Only affects the mixed samples, not the channel capture
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    // Each channel on its own, when capturing them
+    capture_channels: bool,
+    channel_capacitors: [[f32; 2]; 4],
+    channel_samples: [Vec<f32>; 4],
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu {
+            powered: false,
+            square1: Square::default(),
+            square2: Square::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            registers: [0; 0x20],
+            frame_sequencer_step: 0,
+            frame_sequencer_timer: FRAME_SEQUENCER_CYCLES,
+            sample_rate: 0,
+            sample_timer: 0,
+            capacitor: [0.0; 2],
+            capacitor_charge: 1.0,
+            samples: Vec::new(),
+            muted: [false; 4],
+            soloed: [false; 4],
+            capture_channels: false,
+            channel_capacitors: [[0.0; 2]; 4],
+            channel_samples: Default::default(),
+        }
+    }
+}
+
+impl Apu {
+    /// Starts generating samples at the given rate (e.g. 44100). 0 turns it off.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_timer = 0;
+        self.samples.clear();
+        for samples in self.channel_samples.iter_mut() {
+            samples.clear();
+        }
+        if sample_rate > 0 {
+            let cycles_per_sample = CLOCK_SPEED as f32 / sample_rate as f32;
+            self.capacitor_charge = 0.999_958f32.powf(cycles_per_sample);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Number of samples waiting to be taken, counting left and right separately
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Interleaved stereo samples (left, right, left, ...) made since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.index()] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel.index()]
+    }
+
+    /// When any channel is soloed, only the soloed channels are heard
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.soloed[channel.index()] = solo;
+    }
+
+    pub fn is_soloed(&self, channel: Channel) -> bool {
+        self.soloed[channel.index()]
+    }
+
+    /// Whether the channel is part of the mixed output
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        let any_soloed = self.soloed.iter().any(|&solo| solo);
+        if any_soloed {
+            self.is_soloed(channel)
+        } else {
+            !self.is_muted(channel)
+        }
+    }
+
+    /// Also makes samples for each channel on its own, ignoring mute and solo
+    pub fn set_channel_capture(&mut self, capture: bool) {
+        self.capture_channels = capture;
+        self.channel_capacitors = [[0.0; 2]; 4];
+        for samples in self.channel_samples.iter_mut() {
+            samples.clear();
+        }
+    }
+
+    /// Interleaved stereo samples for each channel, in the order of `Channel::ALL`
+    pub fn take_channel_samples(&mut self) -> [Vec<f32>; 4] {
+        std::mem::take(&mut self.channel_samples)
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff26 => {
+                let mut val = 0x70;
+                if self.powered {
+                    val |= 0x80;
+                }
+                for (i, enabled) in self.channels_enabled().iter().enumerate() {
+                    if *enabled {
+                        val |= 1 << i;
+                    }
+                }
+                val
+            }
+            0xff10..=0xff2f => {
+                let index = (addr - 0xff10) as usize;
+                self.registers[index] | READ_MASK[index]
+            }
+            0xff30..=0xff3f => self.wave.ram[(addr - 0xff30) as usize],
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff26 => {
+                let powered = val & 0x80 != 0;
+                if self.powered && !powered {
+                    self.power_off();
+                } else if !self.powered && powered {
+                    self.frame_sequencer_step = 0;
+                    self.frame_sequencer_timer = FRAME_SEQUENCER_CYCLES;
+                }
+                self.powered = powered;
+            }
+            // Wave RAM can be written while the apu is off
+            0xff30..=0xff3f => self.wave.ram[(addr - 0xff30) as usize] = val,
+            // All the other registers are read only while the apu is off
+            _ if !self.powered => (),
+            0xff10..=0xff2f => {
+                self.registers[(addr - 0xff10) as usize] = val;
+                match addr {
+                    0xff10..=0xff14 => self.square1.write(addr - 0xff10, val),
+                    0xff15..=0xff19 => self.square2.write(addr - 0xff15, val),
+                    0xff1a..=0xff1e => self.wave.write(addr - 0xff1a, val),
+                    0xff1f..=0xff23 => self.noise.write(addr - 0xff1f, val),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn power_off(&mut self) {
+        let wave_ram = self.wave.ram;
+        self.square1 = Square::default();
+        self.square2 = Square::default();
+        self.wave = Wave::default();
+        self.wave.ram = wave_ram;
+        self.noise = Noise::default();
+        self.registers = [0; 0x20];
+    }
+
+    fn channels_enabled(&self) -> [bool; 4] {
+        [
+            self.square1.enabled,
+            self.square2.enabled,
+            self.wave.enabled,
+            self.noise.enabled,
+        ]
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if self.powered {
+            self.square1.step(cycles);
+            self.square2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+
+            let mut cycles_left = cycles;
+            while cycles_left >= self.frame_sequencer_timer {
+                cycles_left -= self.frame_sequencer_timer;
+                self.frame_sequencer_timer = FRAME_SEQUENCER_CYCLES;
+                self.clock_frame_sequencer();
+            }
+            self.frame_sequencer_timer -= cycles_left;
+        }
+
+        if self.sample_rate > 0 {
+            self.sample_timer += self.sample_rate as u64 * cycles as u64;
+            while self.sample_timer >= CLOCK_SPEED as u64 {
+                self.sample_timer -= CLOCK_SPEED as u64;
+                self.push_sample();
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        match self.frame_sequencer_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.square1.clock_sweep();
+            }
+            7 => {
+                self.square1.envelope.clock();
+                self.square2.envelope.clock();
+                self.noise.envelope.clock();
+            }
+            _ => (),
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.square1.clock_length();
+        self.square2.clock_length();
+        self.wave.clock_length();
+        self.noise.clock_length();
+    }
+
+    // Converts the digital output (0 - 15) of each channel to analog (-1.0 - 1.0).
+    // A channel with the DAC turned off is silent.
+    fn channel_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, output: u8| {
+            if enabled {
+                output as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        };
+        [
+            dac(self.square1.envelope.dac_enabled(), self.square1.output()),
+            dac(self.square2.envelope.dac_enabled(), self.square2.output()),
+            dac(self.wave.dac_enabled, self.wave.output()),
+            dac(self.noise.envelope.dac_enabled(), self.noise.output()),
+        ]
+    }
+
+    fn push_sample(&mut self) {
+        let outputs = if self.powered {
+            self.channel_outputs()
+        } else {
+            [0.0; 4]
+        };
+        let audible = Channel::ALL.map(|channel| self.is_audible(channel));
+
+        // Right is the lower bits of NR50 and NR51, left the upper
+        for (side, shift) in [(0, 4), (1, 0)].iter().cloned() {
+            let mut mixed = 0.0;
+            for (channel, output) in outputs.iter().enumerate() {
+                if audible[channel] {
+                    mixed += self.panned(channel, shift, *output);
+                }
+            }
+            let output = high_pass(&mut self.capacitor[side], self.capacitor_charge, mixed);
+            self.samples.push(output);
+        }
+
+        if self.capture_channels {
+            for (channel, output) in outputs.iter().enumerate() {
+                for (side, shift) in [(0, 4), (1, 0)].iter().cloned() {
+                    let input = self.panned(channel, shift, *output);
+                    let capacitor = &mut self.channel_capacitors[channel][side];
+                    let output = high_pass(capacitor, self.capacitor_charge, input);
+                    self.channel_samples[channel].push(output);
+                }
+            }
+        }
+    }
+
+    // The part of one side of the mix that comes from the channel
+    fn panned(&self, channel: usize, shift: u8, output: f32) -> f32 {
+        let nr50 = self.registers[0x14];
+        let nr51 = self.registers[0x15];
+        if nr51 & (1 << (channel as u8 + shift)) == 0 {
+            return 0.0;
+        }
+        let volume = ((nr50 >> shift) & 0x07) as f32 + 1.0;
+        output / 4.0 * volume / 8.0
+    }
+}
+
+// High pass filter, removes the DC offset like the capacitor on the real hardware
+fn high_pass(capacitor: &mut f32, charge: f32, input: f32) -> f32 {
+    let output = input - *capacitor;
+    *capacitor = input - output * charge;
+    output
+}
+
+// Only the machine is saved, not the sample rate, mixing or capture settings
+impl Snapshot for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.powered);
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.bytes(&self.registers);
+        state.u8(self.frame_sequencer_step);
+        state.u32(self.frame_sequencer_timer);
+        state.u64(self.sample_timer);
+        state.f32(self.capacitor[0]);
+        state.f32(self.capacitor[1]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.powered = state.bool()?;
+        self.square1.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        state.bytes(&mut self.registers)?;
+        self.frame_sequencer_step = state.u8_at_most(7, "frame sequencer step")?;
+        self.frame_sequencer_timer = state.u32()?;
+        self.sample_timer = state.u64()?;
+        self.capacitor[0] = state.f32()?;
+        self.capacitor[1] = state.f32()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.enabled = state.bool()?;
+        self.counter = state.u16()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.initial_volume);
+        state.bool(self.increase);
+        state.u8(self.period);
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.initial_volume = state.u8_at_most(15, "envelope initial volume")?;
+        self.increase = state.bool()?;
+        self.period = state.u8_at_most(7, "envelope period")?;
+        self.volume = state.u8_at_most(15, "envelope volume")?;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.bool(self.enabled);
+        state.u8(self.timer);
+        state.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.period = state.u8_at_most(7, "sweep period")?;
+        self.negate = state.bool()?;
+        self.shift = state.u8_at_most(7, "sweep shift")?;
+        self.enabled = state.bool()?;
+        self.timer = state.u8()?;
+        self.shadow_frequency = state.u16_at_most(2047, "sweep frequency")?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Square {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.duty);
+        state.u8(self.duty_position);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        self.sweep.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.enabled = state.bool()?;
+        self.duty = state.u8_at_most(3, "square duty")?;
+        self.duty_position = state.u8_at_most(7, "square duty position")?;
+        self.frequency = state.u16_at_most(2047, "square frequency")?;
+        self.timer = state.u32()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.sweep.load_state(state)
+    }
+}
+
+impl Snapshot for Wave {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.volume_code);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+        state.u8(self.position);
+        self.length.save_state(state);
+        state.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.volume_code = state.u8_at_most(3, "wave volume")?;
+        self.frequency = state.u16_at_most(2047, "wave frequency")?;
+        self.timer = state.u32()?;
+        self.position = state.u8_at_most(31, "wave position")?;
+        self.length.load_state(state)?;
+        state.bytes(&mut self.ram)
+    }
+}
+
+impl Snapshot for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.clock_shift);
+        state.bool(self.short_mode);
+        state.u8(self.divisor_code);
+        state.u32(self.timer);
+        state.u16(self.lfsr);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.enabled = state.bool()?;
+        self.clock_shift = state.u8_at_most(15, "noise clock shift")?;
+        self.short_mode = state.bool()?;
+        self.divisor_code = state.u8_at_most(7, "noise divisor")?;
+        self.timer = state.u32()?;
+        self.lfsr = state.u16()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+// A powered on APU at full volume, for tests here and in the audio sinks
+#[cfg(test)]
+pub(crate) fn powered_apu() -> Apu {
+    let mut apu = Apu::default();
+    apu.set_sample_rate(44100);
+    apu.write_register(0xff26, 0x80);
+    // Full volume, all channels on both sides
+    apu.write_register(0xff24, 0x77);
+    apu.write_register(0xff25, 0xff);
+    apu
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_square_channel_makes_sound() {
+        let mut apu = powered_apu();
+        apu.write_register(0xff16, 0x80);
+        apu.write_register(0xff17, 0xf0);
+        apu.write_register(0xff18, 0x00);
+        apu.write_register(0xff19, 0x87);
+        assert_eq!(apu.read_register(0xff26), 0xf2);
+
+        // 1/64 second
+        apu.step(65536);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 689 * 2);
+        assert!(samples.iter().any(|sample| sample.abs() > 0.1));
+    }
+
+    #[test]
+    fn test_load_state_checks_ranges() {
+        // The header is 14 bytes, then comes the enabled flag and the duty
+        let mut state = StateWriter::new(0);
+        Square::default().save_state(&mut state);
+        let mut data = state.into_bytes();
+        data[15] = 4;
+        let mut square = Square::default();
+        assert!(square
+            .load_state(&mut StateReader::new(&data, 0).unwrap())
+            .is_err());
+        data[15] = 3;
+        square
+            .load_state(&mut StateReader::new(&data, 0).unwrap())
+            .unwrap();
+
+        // After the enabled and dac flags, the volume, frequency and timer
+        let mut state = StateWriter::new(0);
+        Wave::default().save_state(&mut state);
+        let mut data = state.into_bytes();
+        data[14 + 2 + 1 + 2 + 4] = 32;
+        assert!(Wave::default()
+            .load_state(&mut StateReader::new(&data, 0).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_mute_and_capture_channel() {
+        let mut apu = powered_apu();
+        apu.set_channel_capture(true);
+        apu.set_muted(Channel::Square2, true);
+        apu.write_register(0xff16, 0x80);
+        apu.write_register(0xff17, 0xf0);
+        apu.write_register(0xff18, 0x00);
+        apu.write_register(0xff19, 0x87);
+
+        apu.step(65536);
+        let loud = |samples: &[f32]| samples.iter().any(|sample| sample.abs() > 0.1);
+        assert!(!loud(&apu.take_samples()));
+        let channels = apu.take_channel_samples();
+        assert_eq!(channels[1].len(), 689 * 2);
+        assert!(loud(&channels[1]));
+        assert!(!loud(&channels[0]));
+
+        // Soloing wins over muting
+        apu.set_solo(Channel::Square2, true);
+        assert!(apu.is_audible(Channel::Square2));
+        assert!(!apu.is_audible(Channel::Square1));
+    }
+
+    #[test]
+    fn test_length_counter_stops_channel() {
+        let mut apu = powered_apu();
+        // Length 63 means 1 tick left
+        apu.write_register(0xff16, 0x3f);
+        apu.write_register(0xff17, 0xf0);
+        apu.write_register(0xff19, 0xc7);
+        assert_eq!(apu.read_register(0xff26) & 0x02, 0x02);
+
+        apu.step(FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.read_register(0xff26) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.write_register(0xff30, 0x12);
+        apu.write_register(0xff12, 0xf3);
+        apu.write_register(0xff26, 0x00);
+
+        assert_eq!(apu.read_register(0xff12), 0x00);
+        assert_eq!(apu.read_register(0xff26), 0x70);
+        assert_eq!(apu.read_register(0xff30), 0x12);
+
+        // Ignored while off
+        apu.write_register(0xff12, 0xf3);
+        assert_eq!(apu.read_register(0xff12), 0x00);
+    }
+}
@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use crate::{apu::Channel, error::Error};
+
+/// Somewhere to send the sound made by the apu
+pub trait AudioSink {
+    /// Interleaved stereo samples (left, right, left, ...) between -1.0 and 1.0
+    fn write(&mut self, samples: &[f32]) -> Result<(), Error>;
+
+    /// Called when there will be no more samples
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Throws away everything
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _samples: &[f32]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Writes 16 bit stereo PCM to a .wav file
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_bytes: u32,
+    finished: bool,
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BYTES_PER_SAMPLE: u16 = 2;
+// The sizes in the header are 32 bits, so the file stops growing at 4 GiB, after about 6.7
+// hours at 44.1 kHz
+const MAX_DATA_BYTES: u32 = (u32::MAX - (WAV_HEADER_SIZE - 8))
+    / (CHANNELS * BYTES_PER_SAMPLE) as u32
+    * (CHANNELS * BYTES_PER_SAMPLE) as u32;
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(filename: &str, sample_rate: u32) -> Result<Self, Error> {
+        WavWriter::new(BufWriter::new(File::create(filename)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> Result<Self, Error> {
+        // The sizes are not known yet, they are filled in by `finish`
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&CHANNELS.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        let block_align = CHANNELS * BYTES_PER_SAMPLE;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer,
+            data_bytes: 0,
+            finished: false,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn write(&mut self, samples: &[f32]) -> Result<(), Error> {
+        let room = ((MAX_DATA_BYTES - self.data_bytes) / BYTES_PER_SAMPLE as u32) as usize;
+        let samples = &samples[..samples.len().min(room)];
+        for sample in samples {
+            let val = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&val.to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u32 * BYTES_PER_SAMPLE as u32;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(WAV_HEADER_SIZE - 8 + self.data_bytes).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A .wav file for each sound channel, named like `<prefix>-square1.wav`
+pub fn channel_wav_writers(
+    prefix: &str,
+    sample_rate: u32,
+) -> Result<[Box<dyn AudioSink>; 4], Error> {
+    let create = |channel: Channel| -> Result<Box<dyn AudioSink>, Error> {
+        let filename = format!("{}-{}.wav", prefix, channel);
+        Ok(Box::new(WavWriter::create(&filename, sample_rate)?))
+    };
+    Ok([
+        create(Channel::Square1)?,
+        create(Channel::Square2)?,
+        create(Channel::Wave)?,
+        create(Channel::Noise)?,
+    ])
+}
+
+#[cfg(feature = "live-audio")]
+pub use self::live::LiveSink;
+
+#[cfg(feature = "live-audio")]
+mod live {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    use super::AudioSink;
+    use crate::error::Error;
+
+    // About 100 ms of stereo audio at 44100 Hz. If the emulator gets ahead of the sound card
+    // the oldest samples are dropped, so the sound does not lag behind.
+    const MAX_BUFFERED: usize = 8820;
+
+    /// Plays the sound on the default output device
+    pub struct LiveSink {
+        buffer: Arc<Mutex<VecDeque<f32>>>,
+        _stream: cpal::Stream,
+    }
+
+    impl LiveSink {
+        pub fn new(sample_rate: u32) -> Result<LiveSink, Error> {
+            let audio_error = |err: &dyn std::fmt::Display| Error::AudioError(err.to_string());
+
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| Error::AudioError("no output device".to_string()))?;
+            let config = cpal::StreamConfig {
+                channels: 2,
+                sample_rate: cpal::SampleRate(sample_rate),
+                buffer_size: cpal::BufferSize::Default,
+            };
+
+            let buffer = Arc::new(Mutex::new(VecDeque::new()));
+            let stream = device
+                .build_output_stream(
+                    &config,
+                    {
+                        let buffer = buffer.clone();
+                        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                            let mut buffer = buffer.lock().unwrap();
+                            for sample in data.iter_mut() {
+                                *sample = buffer.pop_front().unwrap_or(0.0);
+                            }
+                        }
+                    },
+                    |err| eprintln!("Audio error: {}", err),
+                    None,
+                )
+                .map_err(|err| audio_error(&err))?;
+            stream.play().map_err(|err| audio_error(&err))?;
+
+            Ok(LiveSink {
+                buffer,
+                _stream: stream,
+            })
+        }
+    }
+
+    impl AudioSink for LiveSink {
+        fn write(&mut self, samples: &[f32]) -> Result<(), Error> {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.extend(samples);
+            while buffer.len() > MAX_BUFFERED {
+                buffer.pop_front();
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::powered_apu;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write(&[0.0, 1.0, -1.0, 0.5]).unwrap();
+        wav.finish().unwrap();
+        let bytes = wav.into_inner().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &44u32.to_le_bytes());
+        assert_eq!(&bytes[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+        assert_eq!(&bytes[46..48], &i16::MAX.to_le_bytes());
+        assert_eq!(&bytes[48..50], &(-i16::MAX).to_le_bytes());
+    }
+
+    #[test]
+    fn test_wav_samples_from_apu() {
+        let mut apu = powered_apu();
+        // A square wave on channel 2 at full volume
+        apu.write_register(0xff16, 0x80);
+        apu.write_register(0xff17, 0xf0);
+        apu.write_register(0xff18, 0x00);
+        apu.write_register(0xff19, 0x87);
+        apu.step(65536);
+        let samples = apu.take_samples();
+
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write(&samples).unwrap();
+        wav.finish().unwrap();
+        let bytes = wav.into_inner().into_inner();
+
+        let data_bytes = samples.len() as u32 * 2;
+        assert_eq!(&bytes[40..44], &data_bytes.to_le_bytes());
+        let written: Vec<i16> = bytes[44..]
+            .chunks(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+        let expected: Vec<i16> = samples
+            .iter()
+            .map(|sample| (sample * i16::MAX as f32) as i16)
+            .collect();
+        assert_eq!(written, expected);
+        assert!(written.iter().any(|&sample| sample > 3000));
+    }
+
+    #[test]
+    fn test_wav_stops_at_4_gib() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.data_bytes = MAX_DATA_BYTES - 4;
+        wav.write(&[0.5, 0.5, 0.5, 0.5]).unwrap();
+        wav.write(&[0.5, 0.5]).unwrap();
+        wav.finish().unwrap();
+        let bytes = wav.into_inner().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 4);
+        assert_eq!(&bytes[4..8], &(MAX_DATA_BYTES + 36).to_le_bytes());
+        assert_eq!(&bytes[40..44], &MAX_DATA_BYTES.to_le_bytes());
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{error::Error, joypad::Button, palette::Palette};
+
+// Used when no config file is given on the command line, if it exists
+const DEFAULT_CONFIG_FILE: &str = "gbemu.toml";
+
+/// Settings read from a toml config file. Example:
+///
+/// ```toml
+/// palette = "my-palette"
+///
+/// [[palettes]]
+/// name = "my-palette"
+/// colors = ["#ffffff", "#aaaaaa", "#555555", "#000000"]
+///
+/// [keys]
+/// a = "S"
+/// b = "A"
+/// select = "Space"
+/// ```
+///
+/// A button bound to a hotkey's key takes it away from the hotkey, e.g. `Back` from rewind.
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    // Name of the palette to start with
+    #[serde(default)]
+    pub palette: Option<String>,
+    #[serde(default)]
+    palettes: Vec<PaletteConfig>,
+    // Joypad button -> keyboard key, for the buttons that should not use the default key
+    #[serde(default)]
+    pub keys: HashMap<Button, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PaletteConfig {
+    name: String,
+    colors: Vec<String>,
+}
+
+impl Config {
+    pub fn load(filename: Option<&str>) -> Result<Config, Error> {
+        let filename = match filename {
+            Some(filename) => filename,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => DEFAULT_CONFIG_FILE,
+            None => return Ok(Config::default()),
+        };
+
+        let content = fs::read_to_string(filename)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// The builtin palettes followed by the ones from the config file
+    pub fn palettes(&self) -> Result<Vec<Palette>, Error> {
+        let mut palettes = Palette::builtin();
+        for palette in &self.palettes {
+            palettes.push(Palette::from_hex(&palette.name, &palette.colors)?);
+        }
+        Ok(palettes)
+    }
+}
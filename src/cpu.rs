@@ -3,7 +3,10 @@ use std::fmt;
 use crate::{
     error::Error,
     instructions::{Cond, Instruction, Loc16, Loc8},
+    interrupt::Interrupt,
     mem::Mmu,
+    state::{Snapshot, StateReader, StateWriter},
+    symbols::{BankedAddr, Symbols},
 };
 
 #[derive(Default, Debug)]
@@ -18,6 +21,22 @@ pub struct Cpu {
     pub h: u8,
     pub l: u8,
     pub flags: Flags,
+    // Interrupt master enable
+    pub ime: bool,
+    // EI enables interrupts after the instruction following it
+    ime_pending: bool,
+}
+
+/// How the next step moves between functions, so the debugger can follow calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    // A call that is taken. `ret` is where it returns to.
+    Call { to: u16, ret: u16 },
+    // Jumping to an interrupt handler, returning to `ret`
+    Interrupt { to: u16, ret: u16 },
+    // A return that is taken
+    Return,
+    Other,
 }
 
 #[derive(Default, Debug)]
@@ -32,6 +51,40 @@ pub struct Flags {
     carry: bool,
 }
 
+impl Flags {
+    pub fn zero(&self) -> bool {
+        self.zero
+    }
+
+    pub fn subtract(&self) -> bool {
+        self.subtract
+    }
+
+    pub fn half_carry(&self) -> bool {
+        self.half_carry
+    }
+
+    pub fn carry(&self) -> bool {
+        self.carry
+    }
+
+    /// Sets the flags from the F register, the low 4 bits are ignored
+    pub fn set_u8(&mut self, value: u8) {
+        self.zero = value & 0x80 != 0;
+        self.subtract = value & 0x40 != 0;
+        self.half_carry = value & 0x20 != 0;
+        self.carry = value & 0x10 != 0;
+    }
+
+    /// The flags as they are stored in the F register
+    pub fn to_u8(&self) -> u8 {
+        (self.zero as u8) << 7
+            | (self.subtract as u8) << 6
+            | (self.half_carry as u8) << 5
+            | (self.carry as u8) << 4
+    }
+}
+
 impl fmt::Display for Cpu {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "sp: {:04x}", self.sp)?;
@@ -47,17 +100,27 @@ impl fmt::Display for Cpu {
         writeln!(f, "bc: {:04x}", self.get_bc())?;
         writeln!(f, "de: {:04x}", self.get_de())?;
         writeln!(f, "flags: {:?}", self.flags)?;
+        writeln!(f, "ime: {}", self.ime)?;
 
         Ok(())
     }
 }
 
 impl Cpu {
+    pub fn get_af(&self) -> u16 {
+        ((self.a as u16) << 8) + (self.flags.to_u8() as u16)
+    }
+
+    pub fn set_af(&mut self, val: u16) {
+        self.a = (val >> 8) as u8;
+        self.flags.set_u8(val as u8);
+    }
+
     pub fn get_hl(&self) -> u16 {
         ((self.h as u16) << 8) + (self.l as u16)
     }
 
-    fn set_hl(&mut self, val: u16) {
+    pub fn set_hl(&mut self, val: u16) {
         self.h = (val >> 8) as u8;
         self.l = (val & 0xff) as u8;
     }
@@ -66,7 +129,7 @@ impl Cpu {
         ((self.b as u16) << 8) + (self.c as u16)
     }
 
-    fn set_bc(&mut self, val: u16) {
+    pub fn set_bc(&mut self, val: u16) {
         self.b = (val >> 8) as u8;
         self.c = (val & 0xff) as u8;
     }
@@ -75,7 +138,7 @@ impl Cpu {
         ((self.d as u16) << 8) + (self.e as u16)
     }
 
-    fn set_de(&mut self, val: u16) {
+    pub fn set_de(&mut self, val: u16) {
         self.d = (val >> 8) as u8;
         self.e = (val & 0xff) as u8;
     }
@@ -177,33 +240,116 @@ impl Cpu {
         }
     }
 
-    pub fn print_next(&self, mmu: &Mmu) -> Result<(), Error> {
-        let (inst, _) = Instruction::parse(self.pc, &mmu)?;
-        println!("{:04x}    {}", self.pc, inst);
+    pub fn print_next(&self, mmu: &Mmu, symbols: &Symbols) -> Result<(), Error> {
+        let (inst, _) = Instruction::parse(self.pc, mmu)?;
+        if let Some(name) = symbols.name(BankedAddr::new(self.pc, mmu.rom_bank())) {
+            println!("{}:", name);
+        }
+        println!(
+            "{:04x}    {}",
+            self.pc,
+            inst.with_symbols(self.pc, symbols, mmu.rom_bank())
+        );
+        Ok(())
+    }
+
+    /// What the next step will do, without running it
+    pub fn next_flow(&self, mmu: &Mmu) -> Result<Flow, Error> {
+        if self.ime {
+            if let Some(interrupt) = Interrupt::highest_priority(mmu.pending_interrupts()) {
+                return Ok(Flow::Interrupt {
+                    to: interrupt.handler(),
+                    ret: self.pc,
+                });
+            }
+        }
+
+        let (inst, delta) = Instruction::parse(self.pc, &mmu.unrecorded())?;
+        let flow = match inst {
+            Instruction::Call { cond, addr } if self.check_cond(cond) => Flow::Call {
+                to: addr,
+                ret: self.pc.wrapping_add(delta),
+            },
+            Instruction::Restart { addr } => Flow::Call {
+                to: addr,
+                ret: self.pc.wrapping_add(delta),
+            },
+            Instruction::Return { cond } if self.check_cond(cond) => Flow::Return,
+            Instruction::ReturnInterrupt => Flow::Return,
+            _ => Flow::Other,
+        };
+        Ok(flow)
+    }
+
+    /// The value pushed last when the stack pointer is `sp`. A push writes it to the two
+    /// bytes above the new stack pointer.
+    pub fn stack_value(mmu: &Mmu, sp: u16) -> Result<u16, Error> {
+        mmu.read_u16(sp.wrapping_add(1))
+    }
+
+    fn push(&mut self, mmu: &mut Mmu, value: u16) -> Result<(), Error> {
+        mmu.write_u16(self.sp.wrapping_sub(1), value)?;
+        self.sp = self.sp.wrapping_sub(2);
         Ok(())
     }
 
-    pub fn step(&mut self, mmu: &mut Mmu) -> Result<(), Error> {
-        let (inst, delta) = Instruction::parse(self.pc, &mmu)?;
+    fn pop(&mut self, mmu: &Mmu) -> Result<u16, Error> {
+        let value = Cpu::stack_value(mmu, self.sp)?;
+        self.sp = self.sp.wrapping_add(2);
+        Ok(value)
+    }
+
+    // Jumps to the handler of the highest priority pending interrupt, if interrupts are enabled
+    fn handle_interrupts(&mut self, mmu: &mut Mmu) -> Result<Option<u32>, Error> {
+        if !self.ime {
+            return Ok(None);
+        }
+        let interrupt = match Interrupt::highest_priority(mmu.pending_interrupts()) {
+            Some(interrupt) => interrupt,
+            None => return Ok(None),
+        };
+
+        self.ime = false;
+        mmu.acknowledge_interrupt(interrupt);
+        self.push(mmu, self.pc)?;
+        self.pc = interrupt.handler();
+
+        Ok(Some(20))
+    }
+
+    // Runs one instruction, and returns the number of cycles it took
+    pub fn step(&mut self, mmu: &mut Mmu) -> Result<u32, Error> {
+        if let Some(cycles) = self.handle_interrupts(mmu)? {
+            return Ok(cycles);
+        }
+        let mut enable_interrupts = self.ime_pending;
+        self.ime_pending = false;
+
+        // Fetching is not a data access, so watchpoints should not see it
+        let (inst, delta) = Instruction::parse(self.pc, &mmu.unrecorded())?;
         self.pc += delta;
 
         use Instruction::*;
-        match inst {
+        let cycles = match inst {
             Load8 { src, dst } => {
                 let val = self.get_loc8(src, mmu)?;
                 self.set_loc8(dst, mmu, val)?;
+                4 + src.access_cycles() + dst.access_cycles()
             }
             Load16 { src, dst } => {
                 let val = self.get_loc16(src);
                 self.set_loc16(dst, val);
+                12
             }
             Inc8 { loc } => {
                 let val = self.get_loc8(loc, mmu)?;
                 self.set_loc8(loc, mmu, val.wrapping_add(1))?;
+                4 + loc.access_cycles() * 2
             }
             Inc16 { loc } => {
                 let val = self.get_loc16(loc);
                 self.set_loc16(loc, val.wrapping_add(1));
+                8
             }
             Dec8 { loc } => {
                 let val = self.get_loc8(loc, mmu)?;
@@ -211,33 +357,38 @@ impl Cpu {
                 self.set_loc8(loc, mmu, result)?;
                 self.flags.zero = result == 0;
                 self.flags.subtract = true;
-                // set if no borrow from bit 4
-                self.flags.half_carry = result & 0xff != 0xff;
+                // set if borrow from bit 4
+                self.flags.half_carry = val & 0x0f == 0;
+                4 + loc.access_cycles() * 2
             }
-            AddA { src } => {
+            AddA { src: loc } => {
                 let dst = self.a as u16;
-                let src = self.get_loc8(src, mmu)? as u16;
+                let src = self.get_loc8(loc, mmu)? as u16;
                 let result = src + dst;
                 self.a = result as u8;
                 self.flags.zero = self.a == 0;
                 self.flags.subtract = false;
                 self.flags.half_carry = (((src & 0xf) + (dst & 0xf)) & 0x10) == 0x10;
                 self.flags.carry = result & 0x100 == 0x100;
+                4 + loc.access_cycles()
             }
             XOR { src, dst } => {
                 let srcval = self.get_loc8(src, mmu)?;
                 let dstval = self.get_loc8(dst, mmu)?;
                 let res = srcval ^ dstval;
                 self.set_loc8(dst, mmu, res)?;
+                4 + src.access_cycles()
             }
             Sub { src } => {
                 let src_val = self.get_loc8(src, mmu)?;
                 let result = self.a.wrapping_sub(src_val);
                 self.flags.zero = result == 0;
                 self.flags.subtract = true;
-                // TODO: set if no borrow from bit 4
-                // self.flags.half_carry =
+                // set if borrow from bit 4
+                self.flags.half_carry = self.a & 0x0f < src_val & 0x0f;
                 self.flags.carry = self.a < src_val;
+                self.a = result;
+                4 + src.access_cycles()
             }
             Compare { loc } => {
                 // Compare A with n. This is basically an A - n subtraction
@@ -246,15 +397,17 @@ impl Cpu {
                 let result = self.a.wrapping_sub(val1);
                 self.flags.zero = result == 0;
                 self.flags.subtract = true;
-                // TODO: set if no borrow from bit 4
-                // self.flags.half_carry =
+                // set if borrow from bit 4
+                self.flags.half_carry = self.a & 0x0f < val1 & 0x0f;
                 self.flags.carry = self.a < val1;
+                4 + loc.access_cycles()
             }
             CheckBit { bit, loc } => {
                 let mask = 1 << bit;
                 self.flags.zero = self.get_loc8(loc, mmu)? & mask == 0;
                 self.flags.subtract = false;
                 self.flags.half_carry = true;
+                8 + loc.access_cycles()
             }
             RotateLeftCarry { loc } => {
                 let val = self.get_loc8(loc, mmu)?;
@@ -264,6 +417,7 @@ impl Cpu {
                 self.flags.carry = val & 1 == 1;
                 self.flags.subtract = false;
                 self.flags.half_carry = false;
+                8 + loc.access_cycles() * 2
             }
             RotateLeft { loc } => {
                 let val = self.get_loc8(loc, mmu)?;
@@ -277,38 +431,116 @@ impl Cpu {
                 self.flags.carry = new_carry;
                 self.flags.subtract = false;
                 self.flags.half_carry = false;
+                // RLA is a single byte and faster than RL A
+                if delta == 1 {
+                    4
+                } else {
+                    8 + loc.access_cycles() * 2
+                }
             }
             JR { cond, offset } => {
                 if self.check_cond(cond) {
                     self.pc = ((self.pc as i16) + (offset as i16)) as u16;
+                    12
+                } else {
+                    8
                 }
             }
             Call { cond, addr } => {
                 if self.check_cond(cond) {
-                    mmu.write_u16(self.sp - 1, self.pc)?;
-                    self.sp -= 2;
+                    self.push(mmu, self.pc)?;
                     self.pc = addr;
+                    24
+                } else {
+                    12
                 }
             }
+            Restart { addr } => {
+                self.push(mmu, self.pc)?;
+                self.pc = addr;
+                16
+            }
             Return { cond } => {
-                if self.check_cond(cond) {
-                    self.sp += 2;
-                    let addr = mmu.read_u16(self.sp - 1)?;
-                    self.pc = addr;
+                let taken = self.check_cond(cond);
+                if taken {
+                    self.pc = self.pop(mmu)?;
+                }
+                match (cond, taken) {
+                    (Cond::Always, _) => 16,
+                    (_, true) => 20,
+                    (_, false) => 8,
                 }
             }
             Push { loc } => {
                 let value = self.get_loc16(loc);
-                mmu.write_u16(self.sp - 1, value)?;
-                self.sp -= 2;
+                self.push(mmu, value)?;
+                16
             }
             Pop { loc } => {
-                self.sp += 2;
-                let value = mmu.read_u16(self.sp - 1)?;
+                let value = self.pop(mmu)?;
                 self.set_loc16(loc, value);
+                12
+            }
+            DisableInterrupts => {
+                self.ime = false;
+                // EI directly followed by DI never enables interrupts
+                enable_interrupts = false;
+                4
+            }
+            EnableInterrupts => {
+                self.ime_pending = true;
+                4
             }
+            ReturnInterrupt => {
+                self.pc = self.pop(mmu)?;
+                self.ime = true;
+                16
+            }
+        };
+
+        if enable_interrupts {
+            self.ime = true;
         }
 
+        Ok(cycles)
+    }
+}
+
+impl Snapshot for Cpu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.sp);
+        state.u16(self.pc);
+        for &reg in &[self.a, self.b, self.c, self.d, self.e, self.h, self.l] {
+            state.u8(reg);
+        }
+        state.bool(self.flags.zero);
+        state.bool(self.flags.subtract);
+        state.bool(self.flags.half_carry);
+        state.bool(self.flags.carry);
+        state.bool(self.ime);
+        state.bool(self.ime_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        for reg in &mut [
+            &mut self.a,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.e,
+            &mut self.h,
+            &mut self.l,
+        ] {
+            **reg = state.u8()?;
+        }
+        self.flags.zero = state.bool()?;
+        self.flags.subtract = state.bool()?;
+        self.flags.half_carry = state.bool()?;
+        self.flags.carry = state.bool()?;
+        self.ime = state.bool()?;
+        self.ime_pending = state.bool()?;
         Ok(())
     }
 }
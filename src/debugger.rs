@@ -1,23 +1,180 @@
-use std::collections::BTreeSet;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 
 use rustyline::{error::ReadlineError, Editor};
 use structopt::StructOpt;
 
-use crate::{cpu::Cpu, error::Error, mem::Mmu};
+use crate::{
+    apu::Channel,
+    audio,
+    cpu::{Cpu, Flow},
+    display::Command,
+    error::Error,
+    expr::{Expr, Var},
+    gameboy::Gameboy,
+    gdb,
+    instructions::Instruction,
+    mem::{Access, AccessKind, ReadMem},
+    palette::PaletteList,
+    symbols::{self, BankedAddr, Symbols},
+};
+
+// Sourced at the start when no init file is given on the command line, if it exists
+const DEFAULT_INIT_FILE: &str = "gbemu.init";
+const DEFAULT_HISTORY_FILE: &str = "history.txt";
 
 pub struct Debugger {
-    mmu: Mmu,
-    cpu: Cpu,
+    gameboy: Gameboy,
     interrupt: Arc<AtomicBool>,
-    breakpoints: BTreeSet<u16>,
+    // By their number, which is kept when others are deleted
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    next_breakpoint: usize,
+    watchpoints: Vec<Watchpoint>,
+    // The calls we have seen and not yet seen return from, oldest first
+    call_stack: Vec<Frame>,
+    symbols: Symbols,
+    history_file: PathBuf,
+    // Key presses and hotkeys from the window, handled while the game runs
+    commands: Option<(Receiver<Command>, PaletteList)>,
+    // Set by the quit command, ends the session after the current command
+    quit: bool,
+    // The scripts being sourced, so that one cannot source itself
+    sourcing: Vec<PathBuf>,
+}
+
+struct Frame {
+    // Where it was called from, and the function that was called
+    from: u16,
+    to: u16,
+    // The return address, and where it was pushed on the stack
+    ret: u16,
+    sp: u16,
+    // The rom bank when it was called
+    bank: u16,
+    interrupt: bool,
+}
+
+struct Breakpoint {
+    // Without a bank it stops in whatever rom bank is mapped in
+    bank: Option<u16>,
+    addr: u16,
+    // The source, for listing, and the parsed expression
+    condition: Option<(String, Expr)>,
+    enabled: bool,
+    // Times it has been reached with the condition true
+    hits: u64,
+    // Hits left to ignore before it stops
+    ignore: u64,
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(bank) = self.bank {
+            write!(f, "{:02x}:", bank)?;
+        }
+        write!(f, "{:04x}", self.addr)?;
+        if let Some((condition, _)) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        write!(f, ", hit {} times", self.hits)?;
+        if self.ignore > 0 {
+            write!(f, ", ignoring the next {}", self.ignore)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why running stopped
+#[derive(Debug)]
+pub(crate) enum Stop {
+    // What we were asked to run has finished
+    Done,
+    Interrupted,
+    Breakpoint,
+    Watchpoint(WatchKind, Access),
+    // The emulator failed, the error has been printed
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Stops when an instruction reads or writes any address from `start` to `end`
+#[derive(Debug)]
+struct Watchpoint {
+    start: u16,
+    end: u16,
+    kind: WatchKind,
+    // Only stop when this value is read or written
+    value: Option<u8>,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &Access) -> bool {
+        let kind = matches!(
+            (self.kind, access.kind),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, AccessKind::Read)
+                | (WatchKind::Write, AccessKind::Write)
+        );
+        kind && access.addr >= self.start
+            && access.addr <= self.end
+            && self.value.map_or(true, |value| value == access.value)
+    }
+}
+
+impl std::fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "read/write",
+        };
+        write!(f, "{} {:04x}", kind, self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04x}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " == {:02x}", value)?;
+        }
+        Ok(())
+    }
 }
 
 fn parse_hex_16(src: &str) -> Result<u16, std::num::ParseIntError> {
     u16::from_str_radix(src, 16)
 }
 
+fn parse_hex_8(src: &str) -> Result<u8, std::num::ParseIntError> {
+    u8::from_str_radix(src, 16)
+}
+
+// A single address (c000) or an inclusive range (c000-c0ff)
+fn parse_range(src: &str) -> Result<(u16, u16), std::num::ParseIntError> {
+    match src.find('-') {
+        Some(i) => {
+            let start = parse_hex_16(&src[..i])?;
+            let end = parse_hex_16(&src[i + 1..])?;
+            Ok((start.min(end), start.max(end)))
+        }
+        None => {
+            let addr = parse_hex_16(src)?;
+            Ok((addr, addr))
+        }
+    }
+}
+
 // Parse readline commands
 #[derive(StructOpt, Debug)]
 #[structopt(raw(setting = "structopt::clap::AppSettings::NoBinaryName"))]
@@ -26,6 +183,15 @@ enum Opt {
     Run,
     #[structopt(name = "step", alias = "s")]
     Step,
+    /// Step, but run called functions and interrupt handlers until they return
+    #[structopt(name = "next", alias = "n")]
+    Next,
+    /// Run until the current function returns
+    #[structopt(name = "finish")]
+    Finish,
+    /// Run until a label, bank:addr or addr is reached in this function, or it returns
+    #[structopt(name = "until")]
+    Until { location: String },
     #[structopt(name = "reg")]
     PrintRegister { register: String },
     #[structopt(name = "regs")]
@@ -35,30 +201,155 @@ enum Opt {
         #[structopt(parse(try_from_str = "parse_hex_16"))]
         addr: u16,
     },
-    #[structopt(name = "break")]
+    /// Disassemble from a label, bank:addr or addr
+    #[structopt(name = "disas")]
+    Disassemble {
+        location: String,
+        #[structopt(default_value = "10")]
+        count: usize,
+    },
+    /// Show <len> bytes of memory in hex and ascii
+    #[structopt(name = "x")]
+    HexDump { location: String, len: usize },
+    /// Change a register, flag or byte of memory: set a 0x3c, set zf 1, set [c000] [hl] + 1
+    #[structopt(
+        name = "set",
+        raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen")
+    )]
+    Set { target: String, value: Vec<String> },
+    /// Stop at a label, bank:addr or addr, optionally only when a condition is true:
+    /// break 0150 if a == 0x3c
+    #[structopt(
+        name = "break",
+        raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen")
+    )]
     Break {
-        #[structopt(parse(try_from_str = "parse_hex_16"))]
-        addr: u16,
+        location: String,
+        condition: Vec<String>,
+    },
+    #[structopt(name = "breaks")]
+    PrintBreakpoints,
+    #[structopt(name = "break_enable")]
+    EnableBreakpoint { id: usize },
+    #[structopt(name = "break_disable")]
+    DisableBreakpoint { id: usize },
+    #[structopt(name = "break_delete")]
+    DeleteBreakpoint { id: usize },
+    /// Don't stop the next <count> times the breakpoint is hit
+    #[structopt(name = "break_ignore")]
+    IgnoreBreakpoint { id: usize, count: u64 },
+    /// Stop when an address (c000) or range (c000-c0ff) is written
+    #[structopt(name = "watch")]
+    Watch {
+        #[structopt(parse(try_from_str = "parse_range"))]
+        range: (u16, u16),
+        /// Only stop when this value is written
+        #[structopt(long = "value", short = "v", parse(try_from_str = "parse_hex_8"))]
+        value: Option<u8>,
+    },
+    /// Stop when an address or range is read
+    #[structopt(name = "rwatch")]
+    ReadWatch {
+        #[structopt(parse(try_from_str = "parse_range"))]
+        range: (u16, u16),
+        #[structopt(long = "value", short = "v", parse(try_from_str = "parse_hex_8"))]
+        value: Option<u8>,
+    },
+    /// Stop when an address or range is read or written
+    #[structopt(name = "awatch")]
+    AccessWatch {
+        #[structopt(parse(try_from_str = "parse_range"))]
+        range: (u16, u16),
+        #[structopt(long = "value", short = "v", parse(try_from_str = "parse_hex_8"))]
+        value: Option<u8>,
     },
-    #[structopt(name = "break_clear")]
-    ClearBreakepoints,
+    #[structopt(name = "watches")]
+    PrintWatchpoints,
+    #[structopt(name = "watch_clear")]
+    ClearWatchpoints,
+    /// Print the calls that led here
+    #[structopt(name = "bt", alias = "backtrace")]
+    Backtrace,
+    /// Load labels from a symbol file
+    #[structopt(name = "symbols")]
+    LoadSymbols { file: String },
     #[structopt(name = "inst")]
     PrintNextInstruction,
     #[structopt(name = "dumpmem")]
     DumpMemory,
+    #[structopt(name = "mute")]
+    Mute { channel: Channel },
+    #[structopt(name = "unmute")]
+    Unmute { channel: Channel },
+    #[structopt(name = "solo")]
+    Solo { channel: Channel },
+    #[structopt(name = "unsolo")]
+    Unsolo { channel: Channel },
+    #[structopt(name = "channels")]
+    PrintChannels,
+    /// Write each sound channel to <prefix>-<channel>.wav
+    #[structopt(name = "capture")]
+    Capture { prefix: String },
+    #[structopt(name = "capture_stop")]
+    StopCapture,
+    /// Save the machine to a save state slot
+    #[structopt(name = "save")]
+    SaveState { slot: u8 },
+    /// Load a save state slot
+    #[structopt(name = "load")]
+    LoadState { slot: u8 },
+    /// Run the commands in a file
+    #[structopt(name = "source")]
+    Source { file: String },
+    #[structopt(name = "quit", alias = "q")]
+    Quit,
 }
 
 impl Debugger {
-    pub fn new(mmu: Mmu, cpu: Cpu) -> Debugger {
+    pub fn new(gameboy: Gameboy) -> Debugger {
         Debugger {
-            mmu,
-            cpu,
+            gameboy,
             interrupt: Arc::new(AtomicBool::new(false)),
-            breakpoints: BTreeSet::new(),
+            breakpoints: BTreeMap::new(),
+            next_breakpoint: 1,
+            watchpoints: Vec::new(),
+            call_stack: Vec::new(),
+            symbols: Symbols::default(),
+            history_file: PathBuf::from(DEFAULT_HISTORY_FILE),
+            commands: None,
+            quit: false,
+            sourcing: Vec::new(),
+        }
+    }
+
+    /// Forwards the joypad buttons and hotkeys from the window to the game while it runs.
+    /// The speed and rewind hotkeys are ignored, the debugger runs as fast as it can.
+    pub fn set_commands(&mut self, commands: Receiver<Command>, palettes: PaletteList) {
+        self.commands = Some((commands, palettes));
+    }
+
+    /// Where the prompt history is loaded from and saved to, history.txt by default
+    pub fn set_history_file(&mut self, path: PathBuf) {
+        self.history_file = path;
+    }
+
+    /// The init file to source at the start: the given one, or gbemu.init if it exists
+    pub fn init_file(filename: Option<&str>) -> Option<PathBuf> {
+        match filename {
+            Some(filename) => Some(PathBuf::from(filename)),
+            None if Path::new(DEFAULT_INIT_FILE).exists() => Some(PathBuf::from(DEFAULT_INIT_FILE)),
+            None => None,
         }
     }
 
-    pub fn run(mut self) -> Result<(), Error> {
+    /// Labels to show in place of addresses, and to set breakpoints on
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Runs the commands in each script, then reads commands from the prompt until CTRL-D or
+    /// quit. A failing script ends the session.
+    pub fn run(mut self, scripts: &[PathBuf]) -> Result<(), Error> {
         self.interrupt.store(false, Ordering::SeqCst);
         ctrlc::set_handler({
             let interrupt = self.interrupt.clone();
@@ -67,23 +358,41 @@ impl Debugger {
             }
         })?;
 
+        for script in scripts {
+            if let Err(err) = self.source(script) {
+                self.gameboy.finish()?;
+                return Err(err);
+            }
+            if self.quit {
+                break;
+            }
+        }
+        if !self.quit {
+            self.prompt();
+        }
+
+        self.gameboy.finish()?;
+
+        Ok(())
+    }
+
+    fn prompt(&mut self) {
         let mut rl = Editor::<()>::new();
-        if rl.load_history("history.txt").is_err() {
+        if rl.load_history(&self.history_file).is_err() {
             println!("No previous history.");
         }
 
         let mut last = String::new();
 
-        loop {
-            let readline = rl.readline(&format!("{:04x} >> ", self.cpu.pc));
+        while !self.quit {
+            let readline = rl.readline(&format!("{:04x} >> ", self.gameboy.cpu.pc));
             match readline {
                 Ok(line) => {
-                    let line = if line == "" { last } else { line };
+                    let line = if line.trim().is_empty() { last } else { line };
 
                     rl.add_history_entry(&line);
 
-                    let args: Vec<_> = line.split(" ").collect();
-                    if let Err(err) = self.run_command(&args) {
+                    if let Err(err) = self.run_line(&line) {
                         println!("Running command failed: {}", err);
                     }
 
@@ -103,8 +412,60 @@ impl Debugger {
             }
         }
 
-        rl.save_history("history.txt").unwrap();
+        if let Err(err) = rl.save_history(&self.history_file) {
+            println!(
+                "Could not save history to {}: {}",
+                self.history_file.display(),
+                err
+            );
+        }
+    }
+
+    /// Runs the commands in a file, one per line. Empty lines and lines starting with `#`
+    /// are skipped. Stops at the first command that fails, or at quit.
+    pub fn source(&mut self, path: &Path) -> Result<(), Error> {
+        let script = fs::read_to_string(path)?;
+        let canonical = fs::canonicalize(path)?;
+        if self.sourcing.contains(&canonical) {
+            return Err(Error::RecursiveScript(path.display().to_string()));
+        }
+
+        self.sourcing.push(canonical);
+        let res = self.run_script(path, &script);
+        self.sourcing.pop();
+        res
+    }
+
+    fn run_script(&mut self, path: &Path, script: &str) -> Result<(), Error> {
+        for (i, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            println!("{:04x} >> {}", self.gameboy.cpu.pc, line);
+            self.run_line(line).map_err(|err| {
+                Error::ScriptFailed(format!("{}:{}: {}", path.display(), i + 1, err))
+            })?;
+            if self.quit {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_line(&mut self, line: &str) -> Result<(), Error> {
+        let args: Vec<_> = line.split_whitespace().collect();
+        if args.is_empty() {
+            return Ok(());
+        }
+        self.run_command(&args)
+    }
 
+    /// Lets gdb control the machine over its remote protocol instead of the prompt
+    pub fn serve_gdb(mut self, addr: &str) -> Result<(), Error> {
+        gdb::listen(&mut self, addr)?;
+        self.gameboy.finish()?;
         Ok(())
     }
 
@@ -113,21 +474,101 @@ impl Debugger {
 
         use Opt::*;
         match opt {
-            Run => self.game_loop(),
+            Run => {
+                self.run_until(|_, _| false)?;
+            }
             Step => self.step()?,
+            Next => self.next()?,
+            // Depth is below 0 when the return has been run
+            Finish => {
+                self.run_until(|depth, _| depth < 0)?;
+            }
+            Until { location } => {
+                let (bank, addr) = symbols::parse_location(&location, &self.symbols)?;
+                self.run_until(|depth, gameboy| {
+                    let here = BankedAddr::new(gameboy.cpu.pc, gameboy.mmu.rom_bank());
+                    depth < 0
+                        || depth == 0
+                            && here.addr == addr
+                            && bank.map_or(true, |bank| bank == here.bank)
+                })?;
+            }
             PrintRegister { register } => self.print_register(&register),
-            PrintRegisters => println!("{}", self.cpu),
-            PrintMem8 { addr } => println!("(${:04x}) = {:04x}", addr, self.mmu.read_u8(addr)?),
-            PrintNextInstruction => self.cpu.print_next(&self.mmu)?,
-            Break { addr } => {
-                self.breakpoints.insert(addr);
-                println!("Added breakpoint @ {:04x}", addr);
-            }
-            ClearBreakepoints => self.breakpoints.clear(),
+            PrintRegisters => println!("{}", self.gameboy.cpu),
+            PrintMem8 { addr } => {
+                println!("(${:04x}) = {:04x}", addr, self.gameboy.mmu.read_u8(addr)?)
+            }
+            Disassemble { location, count } => {
+                let addr = self.resolve(&location)?;
+                self.disassemble(addr, count)?;
+            }
+            HexDump { location, len } => {
+                let addr = self.resolve(&location)?;
+                self.hex_dump(addr, len)?;
+            }
+            Set { target, value } => self.set(&target, &value.join(" "))?,
+            Backtrace => self.print_backtrace()?,
+            PrintNextInstruction => self.print_next()?,
+            Break {
+                location,
+                condition,
+            } => self.add_breakpoint(&location, &condition)?,
+            LoadSymbols { file } => {
+                self.symbols = Symbols::load(Path::new(&file))?;
+                println!("Loaded {} symbols", self.symbols.len());
+            }
+            PrintBreakpoints => {
+                for (id, breakpoint) in &self.breakpoints {
+                    println!("{}: {}", id, breakpoint);
+                }
+            }
+            EnableBreakpoint { id } => self.breakpoint(id)?.enabled = true,
+            DisableBreakpoint { id } => self.breakpoint(id)?.enabled = false,
+            DeleteBreakpoint { id } => {
+                self.breakpoint(id)?;
+                self.breakpoints.remove(&id);
+            }
+            IgnoreBreakpoint { id, count } => self.breakpoint(id)?.ignore = count,
+            Watch { range, value } => self.add_watchpoint(range, WatchKind::Write, value),
+            ReadWatch { range, value } => self.add_watchpoint(range, WatchKind::Read, value),
+            AccessWatch { range, value } => self.add_watchpoint(range, WatchKind::Access, value),
+            PrintWatchpoints => {
+                for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+                    println!("{}: {}", i, watchpoint);
+                }
+            }
+            ClearWatchpoints => self.watchpoints.clear(),
             DumpMemory => {
-                self.mmu.dump_to_file("dbgdump.hex")?;
+                self.gameboy.mmu.dump_to_file("dbgdump.hex")?;
                 println!("Memory dumped to dbgdump.hex");
             }
+            Mute { channel } => self.gameboy.mmu.apu.set_muted(channel, true),
+            Unmute { channel } => self.gameboy.mmu.apu.set_muted(channel, false),
+            Solo { channel } => self.gameboy.mmu.apu.set_solo(channel, true),
+            Unsolo { channel } => self.gameboy.mmu.apu.set_solo(channel, false),
+            PrintChannels => self.print_channels(),
+            Capture { prefix } => {
+                let sample_rate = match self.gameboy.mmu.apu.sample_rate() {
+                    0 => 44100,
+                    rate => rate,
+                };
+                let sinks = audio::channel_wav_writers(&prefix, sample_rate)?;
+                self.gameboy.start_channel_capture(sinks, sample_rate);
+                println!("Capturing sound channels to {}-<channel>.wav", prefix);
+            }
+            StopCapture => self.gameboy.stop_channel_capture()?,
+            SaveState { slot } => {
+                let path = self.gameboy.save_slot(slot)?;
+                println!("Saved state to {}", path.display());
+            }
+            LoadState { slot } => {
+                let path = self.gameboy.load_slot(slot)?;
+                // We don't know how the game got to where the state was saved
+                self.call_stack.clear();
+                println!("Loaded state from {}", path.display());
+            }
+            Source { file } => self.source(Path::new(&file))?,
+            Quit => self.quit = true,
         };
 
         Ok(())
@@ -135,43 +576,705 @@ impl Debugger {
 
     fn print_register(&self, register: &str) {
         match register.to_lowercase().as_ref() {
-            "a" => println!("a = {:02x}", self.cpu.a),
-            "b" => println!("b = {:02x}", self.cpu.b),
-            "c" => println!("c = {:02x}", self.cpu.c),
-            "d" => println!("d = {:02x}", self.cpu.d),
-            "e" => println!("e = {:02x}", self.cpu.e),
-            "h" => println!("h = {:02x}", self.cpu.h),
-            "l" => println!("l = {:02x}", self.cpu.l),
-            "pc" => println!("pc = {:04x}", self.cpu.pc),
-            "sp" => println!("sp = {:04x}", self.cpu.sp),
-            "hl" => println!("hl = {:04x}", self.cpu.get_hl()),
-            "bc" => println!("bc = {:04x}", self.cpu.get_bc()),
-            "de" => println!("de = {:04x}", self.cpu.get_de()),
+            "a" => println!("a = {:02x}", self.gameboy.cpu.a),
+            "b" => println!("b = {:02x}", self.gameboy.cpu.b),
+            "c" => println!("c = {:02x}", self.gameboy.cpu.c),
+            "d" => println!("d = {:02x}", self.gameboy.cpu.d),
+            "e" => println!("e = {:02x}", self.gameboy.cpu.e),
+            "h" => println!("h = {:02x}", self.gameboy.cpu.h),
+            "l" => println!("l = {:02x}", self.gameboy.cpu.l),
+            "pc" => println!("pc = {:04x}", self.gameboy.cpu.pc),
+            "sp" => println!("sp = {:04x}", self.gameboy.cpu.sp),
+            "hl" => println!("hl = {:04x}", self.gameboy.cpu.get_hl()),
+            "bc" => println!("bc = {:04x}", self.gameboy.cpu.get_bc()),
+            "de" => println!("de = {:04x}", self.gameboy.cpu.get_de()),
             unknown => println!("Unknown register: {}", unknown),
         }
     }
 
+    fn print_channels(&self) {
+        let apu = &self.gameboy.mmu.apu;
+        for &channel in Channel::ALL.iter() {
+            let mut flags = Vec::new();
+            if apu.is_muted(channel) {
+                flags.push("muted");
+            }
+            if apu.is_soloed(channel) {
+                flags.push("solo");
+            }
+            if !apu.is_audible(channel) {
+                flags.push("silent");
+            }
+            println!("{:8} {}", channel.to_string(), flags.join(", "));
+        }
+    }
+
+    pub(crate) fn add_breakpoint(
+        &mut self,
+        location: &str,
+        condition: &[String],
+    ) -> Result<(), Error> {
+        let (bank, addr) = symbols::parse_location(location, &self.symbols)?;
+        let condition = match condition.split_first() {
+            None => None,
+            Some((keyword, expr)) if keyword == "if" => {
+                let src = expr.join(" ");
+                let expr = Expr::parse(&src)?;
+                Some((src, expr))
+            }
+            Some((other, _)) => {
+                return Err(Error::InvalidExpression(format!(
+                    "expected `if` before the condition, found `{}`",
+                    other
+                )))
+            }
+        };
+
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        let breakpoint = Breakpoint {
+            bank,
+            addr,
+            condition,
+            enabled: true,
+            hits: 0,
+            ignore: 0,
+        };
+        println!("Added breakpoint {}: {}", id, breakpoint);
+        self.breakpoints.insert(id, breakpoint);
+        Ok(())
+    }
+
+    /// Deletes the breakpoints at `addr`, and returns true if there were any
+    pub(crate) fn remove_breakpoints_at(&mut self, addr: u16) -> bool {
+        let before = self.breakpoints.len();
+        self.breakpoints
+            .retain(|_, breakpoint| breakpoint.addr != addr);
+        self.breakpoints.len() != before
+    }
+
+    fn breakpoint(&mut self, id: usize) -> Result<&mut Breakpoint, Error> {
+        self.breakpoints
+            .get_mut(&id)
+            .ok_or(Error::Abort("there is no breakpoint with that number"))
+    }
+
+    // Counts the hit if there is a breakpoint at pc whose condition is true, and returns
+    // true if it should stop
+    fn hit_breakpoint(&mut self) -> Result<bool, Error> {
+        let pc = self.gameboy.cpu.pc;
+        let here = BankedAddr::new(pc, self.gameboy.mmu.rom_bank());
+        let gameboy = &self.gameboy;
+        let at_pc = self.breakpoints.iter_mut().filter(|(_, breakpoint)| {
+            breakpoint.enabled
+                && breakpoint.addr == pc
+                && breakpoint.bank.map_or(true, |bank| bank == here.bank)
+        });
+
+        for (id, breakpoint) in at_pc {
+            if let Some((_, condition)) = &breakpoint.condition {
+                if !condition.is_true(gameboy)? {
+                    continue;
+                }
+            }
+            breakpoint.hits += 1;
+            if breakpoint.ignore > 0 {
+                breakpoint.ignore -= 1;
+                continue;
+            }
+            println!(
+                "Breakpoint {} @ {}, hit {} times",
+                id,
+                self.symbols.describe(here),
+                breakpoint.hits
+            );
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn add_watchpoint(
+        &mut self,
+        (start, end): (u16, u16),
+        kind: WatchKind,
+        value: Option<u8>,
+    ) {
+        let watchpoint = Watchpoint {
+            start,
+            end,
+            kind,
+            value,
+        };
+        println!("Added watchpoint {}", watchpoint);
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Deletes the watchpoints on exactly this range, and returns true if there were any
+    pub(crate) fn remove_watchpoint(&mut self, (start, end): (u16, u16), kind: WatchKind) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints
+            .retain(|w| (w.start, w.end, w.kind) != (start, end, kind));
+        self.watchpoints.len() != before
+    }
+
     fn step(&mut self) -> Result<(), Error> {
-        self.cpu.print_next(&self.mmu)?;
-        self.cpu.step(&mut self.mmu)
+        self.print_next()?;
+        self.watched_step()?;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), Error> {
+        match self.gameboy.cpu.next_flow(&self.gameboy.mmu)? {
+            Flow::Call { .. } | Flow::Interrupt { .. } => {
+                self.print_next()?;
+                self.run_until(|depth, _| depth == 0)?;
+                Ok(())
+            }
+            _ => self.step(),
+        }
+    }
+
+    // Runs one instruction and follows it on the call stack. Returns what kind of step it was,
+    // and true if it touched a watchpoint.
+    fn watched_step(&mut self) -> Result<(Flow, Option<(WatchKind, Access)>), Error> {
+        let pc = self.gameboy.cpu.pc;
+        let flow = self.gameboy.cpu.next_flow(&self.gameboy.mmu)?;
+
+        if self.watchpoints.is_empty() {
+            self.gameboy.step()?;
+            self.follow_call(pc, flow);
+            return Ok((flow, None));
+        }
+
+        self.gameboy.mmu.set_watching(true);
+        let res = self.gameboy.step();
+        let accesses = self.gameboy.mmu.take_accesses();
+        self.gameboy.mmu.set_watching(false);
+        res?;
+        self.follow_call(pc, flow);
+
+        for access in accesses {
+            if let Some(watchpoint) = self.watchpoints.iter().find(|w| w.matches(&access)) {
+                let kind = match access.kind {
+                    AccessKind::Read => "Read",
+                    AccessKind::Write => "Write",
+                };
+                println!(
+                    "Watchpoint {} hit by {:04x}: {} (${:04x}) = {:02x}",
+                    watchpoint, pc, kind, access.addr, access.value
+                );
+                return Ok((flow, Some((watchpoint.kind, access))));
+            }
+        }
+        Ok((flow, None))
+    }
+
+    // Updates the call stack after a step from `pc`
+    fn follow_call(&mut self, pc: u16, flow: Flow) {
+        let sp = self.gameboy.cpu.sp;
+        match flow {
+            Flow::Call { to, ret } | Flow::Interrupt { to, ret } => self.call_stack.push(Frame {
+                from: pc,
+                to,
+                ret,
+                sp,
+                bank: self.gameboy.mmu.rom_bank(),
+                interrupt: matches!(flow, Flow::Interrupt { .. }),
+            }),
+            // Everything pushed below the new stack pointer has been returned from, also
+            // when the game has changed the stack pointer itself
+            Flow::Return => {
+                while self.call_stack.last().is_some_and(|frame| frame.sp < sp) {
+                    self.call_stack.pop();
+                }
+            }
+            Flow::Other => (),
+        }
+    }
+
+    // A location as typed by the user, in the bank that is mapped in if it has none
+    fn resolve(&self, location: &str) -> Result<BankedAddr, Error> {
+        let (bank, addr) = symbols::parse_location(location, &self.symbols)?;
+        let mapped = BankedAddr::new(addr, self.gameboy.mmu.rom_bank());
+        Ok(match bank {
+            Some(bank) if mapped.bank != 0 => BankedAddr { bank, addr },
+            _ => mapped,
+        })
+    }
+
+    // Reads from the rom directly when another bank than the one mapped in is asked for
+    fn disassemble(&self, location: BankedAddr, count: usize) -> Result<(), Error> {
+        let bank = location.bank.max(1);
+        let mmu = self.gameboy.mmu.with_rom_bank(bank);
+        let mut addr = location.addr;
+        for _ in 0..count {
+            if let Some(name) = self.symbols.name(BankedAddr::new(addr, bank)) {
+                println!("{}:", name);
+            }
+            let (text, len) = match Instruction::parse(addr, &mmu) {
+                Ok((inst, len)) => (
+                    inst.with_symbols(addr, &self.symbols, bank).to_string(),
+                    len,
+                ),
+                // Data, or an instruction we don't know yet
+                Err(_) => (format!("DB ${:02x}", mmu.read_u8(addr)?), 1),
+            };
+            let bytes = (0..len)
+                .map(|i| Ok(format!("{:02x}", mmu.read_u8(addr.wrapping_add(i))?)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let marker = if addr == self.gameboy.cpu.pc {
+                ">"
+            } else {
+                " "
+            };
+            println!("{} {:04x}  {:<8}  {}", marker, addr, bytes.join(" "), text);
+            addr = addr.wrapping_add(len);
+        }
+        Ok(())
+    }
+
+    fn hex_dump(&self, location: BankedAddr, len: usize) -> Result<(), Error> {
+        let mmu = self.gameboy.mmu.with_rom_bank(location.bank.max(1));
+        let addr = location.addr;
+        let mut bytes = Vec::with_capacity(len);
+        for i in 0..len {
+            bytes.push(mmu.read_u8(addr.wrapping_add(i as u16))?);
+        }
+        for (row, chunk) in bytes.chunks(16).enumerate() {
+            let hex: Vec<_> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            println!(
+                "{:04x}  {:<47}  |{}|",
+                addr.wrapping_add(row as u16 * 16),
+                hex.join(" "),
+                ascii
+            );
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, target: &str, value: &str) -> Result<(), Error> {
+        let value = Expr::parse(value)?.eval(&self.gameboy)?;
+        if target.starts_with('[') && target.ends_with(']') {
+            // Like `[...]` in an expression, but also a label or an address in hex
+            let inner = &target[1..target.len() - 1];
+            let addr = match Expr::parse(inner) {
+                Ok(expr) => expr.eval(&self.gameboy)? as u16,
+                Err(_) => self.resolve(inner)?.addr,
+            };
+            self.gameboy.mmu.write_u8(addr, value as u8)?;
+        } else {
+            let var = Var::parse(&target.to_lowercase())
+                .ok_or_else(|| Error::InvalidExpression(format!("can not set `{}`", target)))?;
+            var.set(&mut self.gameboy, value)?;
+        }
+        Ok(())
+    }
+
+    fn print_next(&self) -> Result<(), Error> {
+        self.gameboy
+            .cpu
+            .print_next(&self.gameboy.mmu, &self.symbols)
+    }
+
+    // The address with the label it is in, if there is one
+    fn describe(&self, addr: u16, bank: u16) -> String {
+        let addr = BankedAddr::new(addr, bank);
+        match self.symbols.nearest(addr) {
+            Some(_) => format!("{:04x} <{}>", addr.addr, self.symbols.describe(addr)),
+            None => format!("{:04x}", addr.addr),
+        }
+    }
+
+    fn print_backtrace(&self) -> Result<(), Error> {
+        let mut pc = self.gameboy.cpu.pc;
+        let mut bank = self.gameboy.mmu.rom_bank();
+        for (i, frame) in self.call_stack.iter().rev().enumerate() {
+            let kind = if frame.interrupt { "interrupt" } else { "call" };
+            print!(
+                "#{:<2} {} in {}, {} from {}",
+                i,
+                self.describe(pc, bank),
+                self.describe(frame.to, frame.bank),
+                kind,
+                self.describe(frame.from, frame.bank)
+            );
+            // The game might have changed the return address or the stack pointer
+            let on_stack = Cpu::stack_value(&self.gameboy.mmu, frame.sp)?;
+            if on_stack != frame.ret {
+                print!(" (returns to {:04x}, expected {:04x})", on_stack, frame.ret);
+            }
+            println!();
+            pc = frame.ret;
+            bank = frame.bank;
+        }
+        println!("#{:<2} {}", self.call_stack.len(), self.describe(pc, bank));
+        Ok(())
+    }
+
+    // Like run_until, but prints the error that stopped the emulator
+    pub(crate) fn game_loop(&mut self, done: impl FnMut(i32, &Gameboy) -> bool) -> Stop {
+        self.run_until(done).unwrap_or_else(|err| {
+            println!("Execution stopped: {}", err);
+            Stop::Error
+        })
     }
 
-    fn game_loop(&mut self) {
+    // Runs until Ctrl-C, a breakpoint or a watchpoint, or until `done` returns true after a
+    // step. `done` gets the call depth relative to where we started.
+    fn run_until(&mut self, mut done: impl FnMut(i32, &Gameboy) -> bool) -> Result<Stop, Error> {
         self.interrupt.store(false, Ordering::SeqCst);
 
-        let res: Result<(), Error> = (|| loop {
+        // We might already be stopped at a breakpoint, which should not stop us again
+        let mut first = true;
+        let mut depth = 0;
+        let mut frame = None;
+        loop {
             if self.interrupt.load(Ordering::SeqCst) {
-                return Ok(());
+                return Ok(Stop::Interrupted);
+            }
+            // Once per frame is often enough for key presses
+            let frame_count = self.gameboy.mmu.ppu.frame_count();
+            if frame != Some(frame_count) {
+                frame = Some(frame_count);
+                self.handle_commands();
             }
-            if self.breakpoints.contains(&self.cpu.pc) {
-                println!("Breakpoint @ {:04x}", self.cpu.pc);
-                return Ok(());
+            if !first && self.hit_breakpoint()? {
+                return Ok(Stop::Breakpoint);
             }
-            self.cpu.step(&mut self.mmu)?;
-        })();
+            first = false;
 
-        if let Err(err) = res {
-            println!("Execution stopped: {}", err);
+            let (flow, watched) = self.watched_step()?;
+            depth += match flow {
+                Flow::Call { .. } | Flow::Interrupt { .. } => 1,
+                Flow::Return => -1,
+                Flow::Other => 0,
+            };
+            if let Some((kind, access)) = watched {
+                return Ok(Stop::Watchpoint(kind, access));
+            }
+            if done(depth, &self.gameboy) {
+                return Ok(Stop::Done);
+            }
         }
     }
+
+    fn handle_commands(&mut self) {
+        let (commands, palettes) = match &mut self.commands {
+            Some(commands) => commands,
+            None => return,
+        };
+        for command in commands.try_iter() {
+            match command {
+                Command::Button(button, pressed) => {
+                    self.gameboy.mmu.joypad.set_button(button, pressed)
+                }
+                Command::CyclePalette => {
+                    let palette = palettes.cycle();
+                    println!("Palette: {}", palette.name);
+                    self.gameboy.mmu.ppu.set_palette(palette.clone());
+                }
+                Command::SaveState(slot) => match self.gameboy.save_slot(slot) {
+                    Ok(path) => println!("Saved state to {}", path.display()),
+                    Err(err) => println!("Saving state failed: {}", err),
+                },
+                Command::LoadState(slot) => match self.gameboy.load_slot(slot) {
+                    Ok(path) => {
+                        self.call_stack.clear();
+                        println!("Loaded state from {}", path.display());
+                    }
+                    Err(err) => println!("Loading state failed: {}", err),
+                },
+                Command::SpeedUp
+                | Command::SlowDown
+                | Command::ResetSpeed
+                | Command::ToggleUncapped
+                | Command::Rewind(_) => {}
+            }
+        }
+    }
+
+    /// Runs one instruction, stopping if it touches a watchpoint
+    pub(crate) fn step_instruction(&mut self) -> Stop {
+        match self.watched_step() {
+            Ok((_, Some((kind, access)))) => Stop::Watchpoint(kind, access),
+            Ok((_, None)) => Stop::Done,
+            Err(err) => {
+                println!("Execution stopped: {}", err);
+                Stop::Error
+            }
+        }
+    }
+
+    pub(crate) fn gameboy(&self) -> &Gameboy {
+        &self.gameboy
+    }
+
+    pub(crate) fn gameboy_mut(&mut self) -> &mut Gameboy {
+        &mut self.gameboy
+    }
+
+    /// Set to stop running, e.g. from another thread
+    pub(crate) fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cpu::Cpu, mem::Mmu};
+
+    fn debugger(rom: Vec<u8>) -> Debugger {
+        Debugger::new(Gameboy::new(Mmu::with_mem(rom), Cpu::default()))
+    }
+
+    #[test]
+    fn test_watchpoint_matches_kind_range_and_value() {
+        let (start, end) = parse_range("c0ff-c000").unwrap();
+        let watchpoint = Watchpoint {
+            start,
+            end,
+            kind: WatchKind::Write,
+            value: Some(0x42),
+        };
+        let access = |addr, kind, value| Access { addr, kind, value };
+
+        assert!(watchpoint.matches(&access(0xc010, AccessKind::Write, 0x42)));
+        assert!(!watchpoint.matches(&access(0xc010, AccessKind::Read, 0x42)));
+        assert!(!watchpoint.matches(&access(0xc010, AccessKind::Write, 0x41)));
+        assert!(!watchpoint.matches(&access(0xc100, AccessKind::Write, 0x42)));
+    }
+
+    #[test]
+    fn test_watchpoints_ignore_fetches_and_traces() {
+        use crate::trace::{TraceFormat, TraceWriter};
+
+        // inc a; ld b,12; ld a,(hl)
+        let mut debugger = debugger(vec![0x3c, 0x06, 0x12, 0x7e]);
+        debugger.gameboy.cpu.set_hl(0xc000);
+        debugger.gameboy.start_trace(TraceWriter::new(
+            Box::new(std::io::sink()),
+            TraceFormat::Doctor,
+        ));
+        debugger.add_watchpoint((0x0001, 0x0003), WatchKind::Access, None);
+        debugger.add_watchpoint((0xc000, 0xc000), WatchKind::Read, None);
+
+        assert_eq!(debugger.watched_step().unwrap().1, None);
+        assert_eq!(debugger.watched_step().unwrap().1, None);
+        let (_, watched) = debugger.watched_step().unwrap();
+        assert_eq!(
+            watched.map(|(kind, access)| (kind, access.addr)),
+            Some((WatchKind::Read, 0xc000))
+        );
+    }
+
+    #[test]
+    fn test_conditional_breakpoint_with_ignore_count() {
+        // inc a; jr -3
+        let mut debugger = debugger(vec![0x3c, 0x18, 0xfd]);
+        let condition = |src: &str| src.split(' ').map(String::from).collect::<Vec<_>>();
+
+        debugger
+            .add_breakpoint("0000", &condition("if a & 1 == 0"))
+            .unwrap();
+        debugger.breakpoint(1).unwrap().ignore = 2;
+        debugger.game_loop(|_, _| false);
+        assert_eq!(debugger.gameboy.cpu.a, 6);
+        assert_eq!(debugger.breakpoints[&1].hits, 3);
+
+        debugger.breakpoint(1).unwrap().enabled = false;
+        debugger.add_breakpoint("0000", &[]).unwrap();
+        debugger.game_loop(|_, _| false);
+        assert_eq!(debugger.gameboy.cpu.a, 7);
+        assert!(debugger
+            .add_breakpoint("0000", &condition("a == 1"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_next_finish_and_until_follow_calls() {
+        let rom = vec![
+            0xcd, 0x08, 0x00, // 0000: call 0008
+            0x3c, // 0003: inc a
+            0x18, 0xfa, // 0004: jr 0000
+            0x00, 0x00, //
+            0x04, // 0008: inc b
+            0xcd, 0x0d, 0x00, // 0009: call 000d
+            0xc9, // 000c: ret
+            0x0c, // 000d: inc c
+            0xc9, // 000e: ret
+        ];
+        let mut debugger = debugger(rom);
+        debugger.gameboy.cpu.sp = 0xfffe;
+
+        debugger.next().unwrap();
+        assert_eq!(debugger.gameboy.cpu.pc, 0x0003);
+        assert_eq!((debugger.gameboy.cpu.b, debugger.gameboy.cpu.c), (1, 1));
+
+        debugger.set_symbols(Symbols::parse("00:0004 Loop\n").unwrap());
+        debugger.run_command(&["until", "Loop"]).unwrap();
+        assert_eq!(debugger.gameboy.cpu.pc, 0x0004);
+        debugger.step().unwrap();
+        debugger.step().unwrap();
+        assert_eq!(debugger.gameboy.cpu.pc, 0x0008);
+
+        debugger.run_command(&["finish"]).unwrap();
+        assert_eq!(debugger.gameboy.cpu.pc, 0x0003);
+        assert_eq!((debugger.gameboy.cpu.b, debugger.gameboy.cpu.c), (2, 2));
+        assert_eq!(debugger.gameboy.cpu.sp, 0xfffe);
+    }
+
+    #[test]
+    fn test_call_stack_follows_rst_call_and_ret() {
+        let mut rom = vec![0; 0x12];
+        rom[0x00..0x03].copy_from_slice(&[0xcf, 0x18, 0xfd]); // rst 08; jr 0000
+        rom[0x08..0x0c].copy_from_slice(&[0xcd, 0x10, 0x00, 0xc9]); // call 0010; ret
+        rom[0x10..0x12].copy_from_slice(&[0x3c, 0xc9]); // inc a; ret
+        let mut debugger = debugger(rom);
+        debugger.gameboy.cpu.sp = 0xfffe;
+
+        debugger.add_breakpoint("0010", &[]).unwrap();
+        debugger.game_loop(|_, _| false);
+        let frames: Vec<_> = debugger
+            .call_stack
+            .iter()
+            .map(|frame| (frame.from, frame.to, frame.ret))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![(0x0000, 0x0008, 0x0001), (0x0008, 0x0010, 0x000b)]
+        );
+
+        debugger.run_command(&["finish"]).unwrap();
+        assert_eq!(debugger.call_stack.len(), 1);
+        debugger.run_command(&["finish"]).unwrap();
+        assert!(debugger.call_stack.is_empty());
+        assert_eq!(debugger.gameboy.cpu.pc, 0x0001);
+    }
+
+    #[test]
+    fn test_breakpoint_on_a_wram_label() {
+        // call d000, where there is a nop and a ret
+        let mut rom = vec![0; 0xd002];
+        rom[0x0000..0x0003].copy_from_slice(&[0xcd, 0x00, 0xd0]);
+        rom[0xd000..0xd002].copy_from_slice(&[0x00, 0xc9]);
+        let mut debugger = debugger(rom);
+        debugger.gameboy.cpu.sp = 0xfffe;
+        debugger.set_symbols(Symbols::parse("01:d000 wCode\n").unwrap());
+
+        debugger.add_breakpoint("wCode", &[]).unwrap();
+        assert!(matches!(debugger.game_loop(|_, _| false), Stop::Breakpoint));
+        assert_eq!(debugger.gameboy.cpu.pc, 0xd000);
+        assert_eq!(debugger.describe(0xd001, 1), "d001 <wCode+1>");
+    }
+
+    #[test]
+    fn test_set_registers_flags_and_memory() {
+        let mut debugger = debugger(vec![0; 0xc002]);
+        debugger.set_symbols(Symbols::parse("00:c001 wFlag\n").unwrap());
+
+        debugger.run_command(&["set", "a", "0x3c"]).unwrap();
+        debugger.run_command(&["set", "hl", "0xc000"]).unwrap();
+        debugger.run_command(&["set", "cf", "1"]).unwrap();
+        debugger
+            .run_command(&["set", "[c000]", "a", "+", "1"])
+            .unwrap();
+        debugger.run_command(&["set", "[wFlag]", "-1"]).unwrap();
+        debugger
+            .run_command(&["set", "[hl]", "[hl]", "+", "1"])
+            .unwrap();
+
+        let cpu = &debugger.gameboy.cpu;
+        assert_eq!(
+            (cpu.a, cpu.get_hl(), cpu.flags.to_u8()),
+            (0x3c, 0xc000, 0x10)
+        );
+        assert_eq!(debugger.gameboy.mmu.read_u16(0xc000).unwrap(), 0xff3e);
+        assert!(debugger.run_command(&["set", "cycles", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_banked_rom() {
+        // ret at the start of bank 2, which needs a whole rom rather than a memory image
+        let mut rom = vec![0; 0xc000];
+        rom[0x8000] = 0xc9;
+        let mmu = Mmu::with_rom(rom);
+        let mut debugger = Debugger::new(Gameboy::new(mmu, Cpu::default()));
+        debugger.run_command(&["disas", "02:4000", "1"]).unwrap();
+        debugger.run_command(&["x", "02:4000", "16"]).unwrap();
+        assert!(debugger.run_command(&["x", "03:4000", "1"]).is_err());
+
+        let location = debugger.resolve("02:4000").unwrap();
+        assert_eq!(
+            location,
+            BankedAddr {
+                bank: 2,
+                addr: 0x4000
+            }
+        );
+        let mmu = &debugger.gameboy.mmu;
+        assert_eq!(mmu.with_rom_bank(2).read_u8(0x4000).unwrap(), 0xc9);
+        assert_eq!(mmu.read_u8(0x4000).unwrap(), 0);
+        assert!(mmu.read_rom_banked(3, 0x4000).is_err());
+        assert_eq!(
+            debugger.resolve("0150").unwrap(),
+            BankedAddr {
+                bank: 0,
+                addr: 0x150
+            }
+        );
+    }
+
+    #[test]
+    fn test_script_stops_at_quit_and_at_errors() {
+        // inc a; jr -3
+        let mut debugger = debugger(vec![0x3c, 0x18, 0xfd]);
+        let script = std::env::temp_dir().join("gbemu-debugger-test.init");
+
+        fs::write(
+            &script,
+            "# count to 3\n\nbreak 0001 if a == 3\n  run\nregs\nquit\nset a 0\n",
+        )
+        .unwrap();
+        debugger.source(&script).unwrap();
+        assert!(debugger.quit);
+        assert_eq!(debugger.gameboy.cpu.a, 3);
+        assert_eq!(debugger.gameboy.cpu.pc, 0x0001);
+
+        debugger.quit = false;
+        fs::write(&script, "set a 7\nnope\nset a 8\n").unwrap();
+        let err = debugger.source(&script).unwrap_err().to_string();
+        fs::remove_file(&script).unwrap();
+        assert!(err.contains(":2:"), "{}", err);
+        assert_eq!(debugger.gameboy.cpu.a, 7);
+    }
+
+    #[test]
+    fn test_script_stops_when_the_emulator_fails() {
+        // nop; an unknown instruction
+        let mut debugger = debugger(vec![0x00, 0xd3]);
+        let script = std::env::temp_dir().join("gbemu-debugger-fail-test.init");
+
+        fs::write(&script, "run\nset a 8\n").unwrap();
+        let err = debugger.source(&script).unwrap_err().to_string();
+        assert!(err.contains(":1:"), "{}", err);
+        assert_eq!(debugger.gameboy.cpu.a, 0);
+
+        fs::write(&script, format!("set a 1\nsource {}\n", script.display())).unwrap();
+        let err = debugger.source(&script).unwrap_err().to_string();
+        fs::remove_file(&script).unwrap();
+        assert!(err.contains(":2:") && err.contains("already"), "{}", err);
+        assert_eq!(debugger.gameboy.cpu.a, 1);
+        assert!(debugger.sourcing.is_empty());
+    }
 }
@@ -1,81 +1,299 @@
-use std::{borrow::Cow, sync::Arc, thread};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+    time::Duration,
+};
 
-use glium::{glutin, implement_vertex, Surface};
-use parking_lot::Mutex;
+use glium::{glutin, texture::Texture2d, Surface};
 
-#[derive(Copy, Clone)]
-struct Vertex {
-    position: [f32; 2],
-    tex_coords: [f32; 2],
+use crate::error::Error;
+use crate::frame::{self, FrameConsumer, FrameProducer};
+use crate::joypad::Button;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Sent from the display window to the emulator when a key is pressed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    // A joypad button was pressed (true) or released (false)
+    Button(Button, bool),
+    CyclePalette,
+    SpeedUp,
+    SlowDown,
+    ResetSpeed,
+    ToggleUncapped,
+    // Save state slots 1 - 9
+    SaveState(u8),
+    LoadState(u8),
+    // Goes back in time while held
+    Rewind(bool),
+}
+
+/// Which keyboard keys are mapped to the joypad buttons
+pub struct KeyBindings {
+    buttons: HashMap<glutin::VirtualKeyCode, Button>,
 }
 
-implement_vertex!(Vertex, position, tex_coords);
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        use glutin::VirtualKeyCode::*;
 
-pub fn start_thread() -> (std::thread::JoinHandle<()>, Arc<Mutex<Vec<u8>>>) {
-    let buffer = Arc::new(Mutex::new(vec![0; 160 * 144 * 3]));
-    let handle = thread::spawn({
-        let buffer = buffer.clone();
-        move || {
-            run(buffer);
+        let buttons = vec![
+            (Right, Button::Right),
+            (Left, Button::Left),
+            (Up, Button::Up),
+            (Down, Button::Down),
+            (X, Button::A),
+            (Z, Button::B),
+            (RShift, Button::Select),
+            (Return, Button::Start),
+        ];
+
+        KeyBindings {
+            buttons: buttons.into_iter().collect(),
         }
+    }
+}
+
+impl KeyBindings {
+    /// The default bindings, with the buttons in `keys` (button -> key name) replaced
+    pub fn with_overrides(keys: &HashMap<Button, String>) -> Result<KeyBindings, Error> {
+        let mut bindings = KeyBindings::default();
+        for (&button, name) in keys {
+            let key = parse_key(name)
+                .ok_or_else(|| Error::InvalidKeyBinding(format!("unknown key `{}`", name)))?;
+            bindings.buttons.retain(|_, bound| *bound != button);
+            bindings.buttons.insert(key, button);
+        }
+        Ok(bindings)
+    }
+
+    fn button(&self, key: glutin::VirtualKeyCode) -> Option<Button> {
+        self.buttons.get(&key).cloned()
+    }
+}
+
+/// Opens the window in its own thread. The window closes when the returned producer is
+/// dropped, and the producer is closed when the window is closed.
+pub fn start_thread(
+    bindings: KeyBindings,
+) -> (
+    std::thread::JoinHandle<()>,
+    FrameProducer,
+    Receiver<Command>,
+) {
+    let (producer, consumer) = frame::channel();
+    let (commands, receiver) = channel();
+    let handle = thread::spawn(move || {
+        run(consumer, commands, bindings);
     });
 
-    (handle, buffer)
+    (handle, producer, receiver)
 }
 
-pub fn run(buffer: Arc<Mutex<Vec<u8>>>) {
+pub fn run(mut frames: FrameConsumer, commands: Sender<Command>, bindings: KeyBindings) {
     let mut events_loop = glutin::EventsLoop::new();
     let window = glutin::WindowBuilder::new();
-    let context = glutin::ContextBuilder::new();
+    let context = glutin::ContextBuilder::new().with_vsync(true);
     let display = glium::Display::new(window, context, &events_loop).unwrap();
 
-    let image: Vec<u8> = vec![
-        0, 0, 0, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 0, 255, 255, 255, 0,
-        0, 0, 0, 0, 0, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 0, 255, 255,
-        255, 0, 0, 0,
-    ];
-
-    // let image = glium::texture::RawImage2d::from_raw_rgb(image, (4, 4));
-    //let texture = glium::texture::Texture2d::new(&display, image).unwrap();
+    let texture = Texture2d::empty_with_format(
+        &display,
+        glium::texture::UncompressedFloatFormat::U8U8U8,
+        glium::texture::MipmapsOption::NoMipmap,
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    )
+    .unwrap();
+    let mut shown_sequence = 0;
 
     let mut closed = false;
-    while !closed {
-        let texture = {
-            let buffer = buffer.lock();
-            let image = glium::texture::RawImage2d {
-                data: Cow::Borrowed(&buffer),
-                width: 160,
-                height: 144,
-                format: glium::texture::ClientFormat::U8U8U8,
-            };
-            glium::texture::Texture2d::new(&display, image).unwrap()
-        };
+    while !closed && !frames.is_closed() {
+        let mut redraw = false;
+
+        // Only upload to the gpu when the emulator has finished a new frame
+        match frames.latest() {
+            Some(frame) if frame.sequence != shown_sequence => {
+                let image = glium::texture::RawImage2d {
+                    data: Cow::Borrowed(&frame.pixels),
+                    width: SCREEN_WIDTH as u32,
+                    height: SCREEN_HEIGHT as u32,
+                    format: glium::texture::ClientFormat::U8U8U8,
+                };
+                texture.write(
+                    glium::Rect {
+                        left: 0,
+                        bottom: 0,
+                        width: SCREEN_WIDTH as u32,
+                        height: SCREEN_HEIGHT as u32,
+                    },
+                    image,
+                );
+                shown_sequence = frame.sequence;
+                redraw = true;
+            }
+            _ => (),
+        }
 
-        let target = display.draw();
+        events_loop.poll_events(|ev| {
+            if let glutin::Event::WindowEvent { event, .. } = ev {
+                match event {
+                    glutin::WindowEvent::CloseRequested => closed = true,
+                    glutin::WindowEvent::Resized(_) | glutin::WindowEvent::Refresh => redraw = true,
+                    glutin::WindowEvent::KeyboardInput {
+                        input:
+                            glutin::KeyboardInput {
+                                state,
+                                virtual_keycode: Some(key),
+                                modifiers,
+                                ..
+                            },
+                        ..
+                    } => {
+                        let pressed = state == glutin::ElementState::Pressed;
+                        let command = match bindings.button(key) {
+                            Some(button) => Some(Command::Button(button, pressed)),
+                            None if key == glutin::VirtualKeyCode::Back => {
+                                Some(Command::Rewind(pressed))
+                            }
+                            None if pressed => hotkey(key, modifiers),
+                            None => None,
+                        };
+                        if let Some(command) = command {
+                            // The emulator might have stopped, then there is no one to tell
+                            let _ = commands.send(command);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        });
 
-        let (target_w, target_h) = target.get_dimensions();
+        if redraw {
+            draw(&display, &texture);
+        } else {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
 
-        texture.as_surface().blit_whole_color_to(
-            &target,
-            &glium::BlitTarget {
-                left: 0,
-                bottom: target_h,
-                width: target_w as i32,
-                height: -(target_h as i32),
-            },
-            glium::uniforms::MagnifySamplerFilter::Nearest,
-        );
+fn draw(display: &glium::Display, texture: &Texture2d) {
+    let target = display.draw();
 
-        target.finish().unwrap();
+    let (target_w, target_h) = target.get_dimensions();
 
-        events_loop.poll_events(|ev| match ev {
-            glutin::Event::WindowEvent { event, .. } => match event {
-                glutin::WindowEvent::CloseRequested => closed = true,
-                _ => (),
-            },
-            _ => (),
-        });
+    texture.as_surface().blit_whole_color_to(
+        &target,
+        &glium::BlitTarget {
+            left: 0,
+            bottom: target_h,
+            width: target_w as i32,
+            height: -(target_h as i32),
+        },
+        glium::uniforms::MagnifySamplerFilter::Nearest,
+    );
+
+    target.finish().unwrap();
+}
+
+// F1 - F9 loads the save state in that slot, with shift it saves instead
+fn hotkey(key: glutin::VirtualKeyCode, modifiers: glutin::ModifiersState) -> Option<Command> {
+    if let Some(slot) = function_key_number(key) {
+        return if modifiers.shift {
+            Some(Command::SaveState(slot))
+        } else {
+            Some(Command::LoadState(slot))
+        };
+    }
 
-        thread::sleep_ms(30);
+    match key {
+        glutin::VirtualKeyCode::P => Some(Command::CyclePalette),
+        glutin::VirtualKeyCode::Equals | glutin::VirtualKeyCode::Add => Some(Command::SpeedUp),
+        glutin::VirtualKeyCode::Minus | glutin::VirtualKeyCode::Subtract => Some(Command::SlowDown),
+        glutin::VirtualKeyCode::Key0 => Some(Command::ResetSpeed),
+        glutin::VirtualKeyCode::Tab => Some(Command::ToggleUncapped),
+        _ => None,
     }
 }
+
+fn function_key_number(key: glutin::VirtualKeyCode) -> Option<u8> {
+    use glutin::VirtualKeyCode::*;
+
+    let number = match key {
+        F1 => 1,
+        F2 => 2,
+        F3 => 3,
+        F4 => 4,
+        F5 => 5,
+        F6 => 6,
+        F7 => 7,
+        F8 => 8,
+        F9 => 9,
+        _ => return None,
+    };
+    Some(number)
+}
+
+// Key names as used in the config file, e.g. "Z", "Up", "Return" or "RShift"
+fn parse_key(name: &str) -> Option<glutin::VirtualKeyCode> {
+    use glutin::VirtualKeyCode::*;
+
+    let key = match name.to_lowercase().as_str() {
+        "a" => A,
+        "b" => B,
+        "c" => C,
+        "d" => D,
+        "e" => E,
+        "f" => F,
+        "g" => G,
+        "h" => H,
+        "i" => I,
+        "j" => J,
+        "k" => K,
+        "l" => L,
+        "m" => M,
+        "n" => N,
+        "o" => O,
+        "p" => P,
+        "q" => Q,
+        "r" => R,
+        "s" => S,
+        "t" => T,
+        "u" => U,
+        "v" => V,
+        "w" => W,
+        "x" => X,
+        "y" => Y,
+        "z" => Z,
+        "1" => Key1,
+        "2" => Key2,
+        "3" => Key3,
+        "4" => Key4,
+        "5" => Key5,
+        "6" => Key6,
+        "7" => Key7,
+        "8" => Key8,
+        "9" => Key9,
+        "up" => Up,
+        "down" => Down,
+        "left" => Left,
+        "right" => Right,
+        "return" | "enter" => Return,
+        "space" => Space,
+        "back" | "backspace" => Back,
+        "lshift" => LShift,
+        "rshift" => RShift,
+        "lcontrol" => LControl,
+        "rcontrol" => RControl,
+        "lalt" => LAlt,
+        "ralt" => RAlt,
+        "comma" => Comma,
+        "period" => Period,
+        "semicolon" => Semicolon,
+        "slash" => Slash,
+        _ => return None,
+    };
+
+    Some(key)
+}
@@ -5,6 +5,24 @@ pub enum Error {
     IoError(std::io::Error),
     CtrlCError(ctrlc::Error),
     ClapError(clap::Error),
+    PngError(png::EncodingError),
+    ConfigError(toml::de::Error),
+    InvalidRom(String),
+    InvalidPalette(String),
+    InvalidKeyBinding(String),
+    AudioError(String),
+    UnknownChannel(String),
+    InvalidSaveState(String),
+    UnsupportedSaveStateVersion(u32),
+    InvalidMovie(String),
+    InvalidExpression(String),
+    InvalidSymbolFile(String),
+    UnknownSymbol(String),
+    NoSuchRomBank(u16),
+    UnknownTraceFormat(String),
+    InvalidTrace(String),
+    ScriptFailed(String),
+    RecursiveScript(String),
     UnknownInstruction(u8),
     UnknownCbInstruction(u8),
     InvalidReadFromMemoryLocation(u16),
@@ -28,6 +46,32 @@ impl fmt::Display for Error {
             Error::IoError(original) => write!(f, "IO Error: {}", original),
             Error::ClapError(original) => write!(f, "ClapError: {}", original),
             Error::CtrlCError(original) => write!(f, "CtrlCError: {}", original),
+            Error::PngError(original) => write!(f, "PngError: {}", original),
+            Error::ConfigError(original) => write!(f, "ConfigError: {}", original),
+            Error::InvalidRom(msg) => write!(f, "Invalid rom: {}", msg),
+            Error::InvalidPalette(msg) => write!(f, "Invalid palette: {}", msg),
+            Error::InvalidKeyBinding(msg) => write!(f, "Invalid key binding: {}", msg),
+            Error::AudioError(msg) => write!(f, "Audio error: {}", msg),
+            Error::UnknownChannel(name) => write!(f, "Unknown sound channel: {}", name),
+            Error::InvalidSaveState(msg) => write!(f, "Invalid save state: {}", msg),
+            Error::InvalidMovie(msg) => write!(f, "Invalid movie: {}", msg),
+            Error::InvalidExpression(msg) => write!(f, "Invalid expression: {}", msg),
+            Error::InvalidSymbolFile(msg) => write!(f, "Invalid symbol file: {}", msg),
+            Error::UnknownTraceFormat(name) => {
+                write!(f, "Unknown trace format `{}`, use doctor or rich", name)
+            }
+            Error::InvalidTrace(msg) => write!(f, "Invalid trace: {}", msg),
+            Error::ScriptFailed(msg) => write!(f, "Script failed at {}", msg),
+            Error::RecursiveScript(path) => write!(f, "Script {} is already being run", path),
+            Error::NoSuchRomBank(bank) => write!(f, "The rom has no bank {}", bank),
+            Error::UnknownSymbol(name) => write!(f, "Unknown symbol or address: {}", name),
+            Error::UnsupportedSaveStateVersion(version) => write!(
+                f,
+                "Save state version {} is not supported, this version of gbemu reads {} - {}",
+                version,
+                crate::state::MIN_VERSION,
+                crate::state::VERSION
+            ),
             Error::Abort(msg) => write!(f, "Aborting, {}", msg),
         }
     }
@@ -52,3 +96,15 @@ impl From<clap::Error> for Error {
         Error::ClapError(error)
     }
 }
+
+impl From<png::EncodingError> for Error {
+    fn from(error: png::EncodingError) -> Self {
+        Error::PngError(error)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Error::ConfigError(error)
+    }
+}
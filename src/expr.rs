@@ -0,0 +1,430 @@
+use std::fmt;
+
+use crate::{error::Error, gameboy::Gameboy};
+
+/// An expression over the state of the machine, used for conditional breakpoints.
+///
+/// Numbers are decimal, or hex with a `0x` or `$` prefix. The registers are named as usual
+/// (`a`, `f`, `hl`, `sp`, `pc`, ...), the flags are `zf`, `nf`, `hf` and `cf`, `[addr]` reads a
+/// byte from memory, `cycles` is the cycle counter and `bank` the current rom bank.
+/// Comparisons and `&&`, `||` and `!` give 1 for true and 0 for false.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(u64),
+    Var(Var),
+    // A byte from memory
+    Deref(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Var {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    ZeroFlag,
+    SubtractFlag,
+    HalfCarryFlag,
+    CarryFlag,
+    Ime,
+    Cycles,
+    RomBank,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+    Complement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+impl Var {
+    pub fn parse(name: &str) -> Option<Var> {
+        use Var::*;
+
+        let var = match name {
+            "a" => A,
+            "b" => B,
+            "c" => C,
+            "d" => D,
+            "e" => E,
+            "f" => F,
+            "h" => H,
+            "l" => L,
+            "af" => AF,
+            "bc" => BC,
+            "de" => DE,
+            "hl" => HL,
+            "sp" => SP,
+            "pc" => PC,
+            "zf" => ZeroFlag,
+            "nf" => SubtractFlag,
+            "hf" => HalfCarryFlag,
+            "cf" => CarryFlag,
+            "ime" => Ime,
+            "cycles" => Cycles,
+            "bank" => RomBank,
+            _ => return None,
+        };
+        Some(var)
+    }
+
+    /// Changes a register or flag. Values too big for it are cut off, like the cpu would.
+    pub fn set(self, gameboy: &mut Gameboy, value: u64) -> Result<(), Error> {
+        let cpu = &mut gameboy.cpu;
+        let flag = |bit: u8| {
+            let f = cpu.flags.to_u8() & !bit;
+            if value != 0 {
+                f | bit
+            } else {
+                f
+            }
+        };
+        match self {
+            Var::A => cpu.a = value as u8,
+            Var::B => cpu.b = value as u8,
+            Var::C => cpu.c = value as u8,
+            Var::D => cpu.d = value as u8,
+            Var::E => cpu.e = value as u8,
+            Var::F => cpu.flags.set_u8(value as u8),
+            Var::H => cpu.h = value as u8,
+            Var::L => cpu.l = value as u8,
+            Var::AF => cpu.set_af(value as u16),
+            Var::BC => cpu.set_bc(value as u16),
+            Var::DE => cpu.set_de(value as u16),
+            Var::HL => cpu.set_hl(value as u16),
+            Var::SP => cpu.sp = value as u16,
+            Var::PC => cpu.pc = value as u16,
+            Var::ZeroFlag => cpu.flags.set_u8(flag(0x80)),
+            Var::SubtractFlag => cpu.flags.set_u8(flag(0x40)),
+            Var::HalfCarryFlag => cpu.flags.set_u8(flag(0x20)),
+            Var::CarryFlag => cpu.flags.set_u8(flag(0x10)),
+            Var::Ime => cpu.ime = value != 0,
+            Var::Cycles | Var::RomBank => {
+                return Err(Error::InvalidExpression(
+                    "the cycle counter and rom bank can not be set".to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn value(self, gameboy: &Gameboy) -> u64 {
+        let cpu = &gameboy.cpu;
+        let value = match self {
+            Var::A => cpu.a as u16,
+            Var::B => cpu.b as u16,
+            Var::C => cpu.c as u16,
+            Var::D => cpu.d as u16,
+            Var::E => cpu.e as u16,
+            Var::F => cpu.flags.to_u8() as u16,
+            Var::H => cpu.h as u16,
+            Var::L => cpu.l as u16,
+            Var::AF => cpu.get_af(),
+            Var::BC => cpu.get_bc(),
+            Var::DE => cpu.get_de(),
+            Var::HL => cpu.get_hl(),
+            Var::SP => cpu.sp,
+            Var::PC => cpu.pc,
+            Var::ZeroFlag => cpu.flags.zero() as u16,
+            Var::SubtractFlag => cpu.flags.subtract() as u16,
+            Var::HalfCarryFlag => cpu.flags.half_carry() as u16,
+            Var::CarryFlag => cpu.flags.carry() as u16,
+            Var::Ime => cpu.ime as u16,
+            Var::Cycles => return gameboy.cycles(),
+            Var::RomBank => gameboy.mmu.rom_bank(),
+        };
+        value as u64
+    }
+}
+
+impl Expr {
+    pub fn parse(src: &str) -> Result<Expr, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(src)?,
+            pos: 0,
+        };
+        let expr = parser.expr(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(Error::InvalidExpression(format!("unexpected `{}`", token))),
+        }
+    }
+
+    pub fn eval(&self, gameboy: &Gameboy) -> Result<u64, Error> {
+        let value = match self {
+            Expr::Number(value) => *value,
+            Expr::Var(var) => var.value(gameboy),
+            Expr::Deref(addr) => gameboy.mmu.read_u8(addr.eval(gameboy)? as u16)? as u64,
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(gameboy)?;
+                match op {
+                    UnaryOp::Not => (value == 0) as u64,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Complement => !value,
+                }
+            }
+            // Evaluated on their own so the right side is not read when it is not needed
+            Expr::Binary(BinaryOp::And, left, right) => {
+                (left.eval(gameboy)? != 0 && right.eval(gameboy)? != 0) as u64
+            }
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                (left.eval(gameboy)? != 0 || right.eval(gameboy)? != 0) as u64
+            }
+            Expr::Binary(op, left, right) => {
+                let left = left.eval(gameboy)?;
+                let right = right.eval(gameboy)?;
+                match op {
+                    BinaryOp::Eq => (left == right) as u64,
+                    BinaryOp::Ne => (left != right) as u64,
+                    BinaryOp::Lt => (left < right) as u64,
+                    BinaryOp::Le => (left <= right) as u64,
+                    BinaryOp::Gt => (left > right) as u64,
+                    BinaryOp::Ge => (left >= right) as u64,
+                    BinaryOp::BitOr => left | right,
+                    BinaryOp::BitXor => left ^ right,
+                    BinaryOp::BitAnd => left & right,
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Sub => left.wrapping_sub(right),
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    /// Evaluates the expression as a condition, anything but 0 is true
+    pub fn is_true(&self, gameboy: &Gameboy) -> Result<bool, Error> {
+        Ok(self.eval(gameboy)? != 0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u64),
+    Ident(String),
+    // Operators and brackets
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Longest first, so `<=` is not read as `<` followed by `=`
+const SYMBOLS: [&str; 20] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "~", "-", "+", "&", "|", "^", "(", ")", "[",
+    "]", "=",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut rest = src;
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '$' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            rest = &rest[len..];
+            tokens.push(parse_word(word)?);
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            // A single = is always a mistake for ==
+            if *symbol == "=" {
+                return Err(Error::InvalidExpression("use `==` to compare".to_string()));
+            }
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            return Err(Error::InvalidExpression(format!("unexpected `{}`", c)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_word(word: &str) -> Result<Token, Error> {
+    let lower = word.to_lowercase();
+    let number = if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        u64::from_str_radix(hex, 16)
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+        lower.parse()
+    } else {
+        return Ok(Token::Ident(lower));
+    };
+    number
+        .map(Token::Number)
+        .map_err(|_| Error::InvalidExpression(format!("invalid number `{}`", word)))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+// From the loosest binding to the tightest
+const PRECEDENCE: [&[(&str, BinaryOp)]; 7] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[
+        ("==", BinaryOp::Eq),
+        ("!=", BinaryOp::Ne),
+        ("<", BinaryOp::Lt),
+        ("<=", BinaryOp::Le),
+        (">", BinaryOp::Gt),
+        (">=", BinaryOp::Ge),
+    ],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+];
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, Error> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| Error::InvalidExpression("unexpected end".to_string()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), Error> {
+        match self.next()? {
+            Token::Symbol(found) if found == symbol => Ok(()),
+            token => Err(Error::InvalidExpression(format!(
+                "expected `{}`, found `{}`",
+                symbol, token
+            ))),
+        }
+    }
+
+    fn expr(&mut self, level: usize) -> Result<Expr, Error> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut left = self.expr(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol(symbol)) => PRECEDENCE[level]
+                    .iter()
+                    .find(|(op_symbol, _)| op_symbol == symbol)
+                    .map(|&(_, op)| op),
+                _ => None,
+            };
+            match op {
+                Some(op) => {
+                    self.pos += 1;
+                    let right = self.expr(level + 1)?;
+                    left = Expr::Binary(op, Box::new(left), Box::new(right));
+                }
+                None => return Ok(left),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        match self.next()? {
+            Token::Number(value) => Ok(Expr::Number(value)),
+            Token::Ident(name) => Var::parse(&name)
+                .map(Expr::Var)
+                .ok_or_else(|| Error::InvalidExpression(format!("unknown name `{}`", name))),
+            Token::Symbol("!") => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?))),
+            Token::Symbol("-") => Ok(Expr::Unary(UnaryOp::Negate, Box::new(self.unary()?))),
+            Token::Symbol("~") => Ok(Expr::Unary(UnaryOp::Complement, Box::new(self.unary()?))),
+            Token::Symbol("(") => {
+                let expr = self.expr(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Symbol("[") => {
+                let expr = self.expr(0)?;
+                self.expect("]")?;
+                Ok(Expr::Deref(Box::new(expr)))
+            }
+            token => Err(Error::InvalidExpression(format!("unexpected `{}`", token))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cpu::Cpu, mem::Mmu};
+
+    fn gameboy() -> Gameboy {
+        let mut mem = vec![0; 0xc010];
+        mem[0xc005] = 0x12;
+        let mut gameboy = Gameboy::new(Mmu::with_mem(mem), Cpu::default());
+        gameboy.cpu.a = 0x3c;
+        gameboy.cpu.h = 0xc0;
+        gameboy.cpu.l = 0x05;
+        gameboy
+    }
+
+    fn eval(src: &str) -> u64 {
+        Expr::parse(src).unwrap().eval(&gameboy()).unwrap()
+    }
+
+    #[test]
+    fn test_evaluates_registers_memory_and_operators() {
+        assert_eq!(eval("a == 0x3c && [hl] != 0"), 1);
+        assert_eq!(eval("[hl + 1] == 0 || a < $10"), 1);
+        assert_eq!(eval("a == 60 && !(hl == $c005)"), 0);
+        assert_eq!(eval("1 + 2 == 3 & 1"), 0);
+        assert_eq!(eval("hl - 5 + [$c005]"), 0xc012);
+        assert_eq!(eval("zf | bank"), 1);
+    }
+
+    #[test]
+    fn test_rejects_invalid_expressions() {
+        for src in &["a = 1", "a ==", "(a", "foo", "0xzz", "a b"] {
+            assert!(Expr::parse(src).is_err(), "{}", src);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Set on the middle index when it holds a frame the consumer has not seen yet
+const NEW_FRAME: usize = 0b100;
+const INDEX_MASK: usize = 0b011;
+
+/// One finished frame, 160x144 RGB pixels
+pub struct Frame {
+    pub pixels: Vec<u8>,
+    // Starts at 1 for the first presented frame
+    pub sequence: u64,
+}
+
+// Triple buffering. The producer owns the back buffer, the consumer owns the front buffer,
+// and the last one is in the middle. Ownership is handed over by atomically swapping
+// indexes with the middle, so each buffer is only ever touched by one side at a time.
+struct Shared {
+    buffers: [UnsafeCell<Frame>; 3],
+    middle: AtomicUsize,
+    closed: AtomicBool,
+}
+
+// Safe since a buffer is only accessed by the side that owns its index
+unsafe impl Sync for Shared {}
+
+pub struct FrameProducer {
+    shared: Arc<Shared>,
+    back: usize,
+    sequence: u64,
+}
+
+pub struct FrameConsumer {
+    shared: Arc<Shared>,
+    front: usize,
+}
+
+/// Creates the two ends of a frame channel. When either end is dropped the channel is closed,
+/// which tells the other side to shut down.
+pub fn channel() -> (FrameProducer, FrameConsumer) {
+    let frame = || {
+        UnsafeCell::new(Frame {
+            pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+            sequence: 0,
+        })
+    };
+    let shared = Arc::new(Shared {
+        buffers: [frame(), frame(), frame()],
+        middle: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+
+    let producer = FrameProducer {
+        shared: shared.clone(),
+        back: 0,
+        sequence: 0,
+    };
+    let consumer = FrameConsumer { shared, front: 2 };
+
+    (producer, consumer)
+}
+
+impl FrameProducer {
+    /// The buffer to draw the next frame into
+    pub fn back_buffer(&mut self) -> &mut [u8] {
+        unsafe { &mut (*self.shared.buffers[self.back].get()).pixels }
+    }
+
+    /// Hands the back buffer over to the consumer, replacing any frame it has not picked up yet
+    pub fn present(&mut self) {
+        self.sequence += 1;
+        unsafe {
+            (*self.shared.buffers[self.back].get()).sequence = self.sequence;
+        }
+        let old = self
+            .shared
+            .middle
+            .swap(self.back | NEW_FRAME, Ordering::AcqRel);
+        self.back = old & INDEX_MASK;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for FrameProducer {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+impl FrameConsumer {
+    /// Returns the newest frame, if a new one has been presented since the last call
+    pub fn latest(&mut self) -> Option<&Frame> {
+        if self.shared.middle.load(Ordering::Acquire) & NEW_FRAME == 0 {
+            return None;
+        }
+        let old = self.shared.middle.swap(self.front, Ordering::AcqRel);
+        self.front = old & INDEX_MASK;
+        unsafe { Some(&*self.shared.buffers[self.front].get()) }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for FrameConsumer {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_only_newest_frame_is_returned() {
+        let (mut producer, mut consumer) = channel();
+        assert!(consumer.latest().is_none());
+
+        producer.back_buffer()[0] = 1;
+        producer.present();
+        producer.back_buffer()[0] = 2;
+        producer.present();
+
+        let frame = consumer.latest().unwrap();
+        assert_eq!(frame.sequence, 2);
+        assert_eq!(frame.pixels[0], 2);
+        assert!(consumer.latest().is_none());
+
+        producer.back_buffer()[0] = 3;
+        producer.present();
+        assert_eq!(consumer.latest().unwrap().pixels[0], 3);
+    }
+
+    #[test]
+    fn test_closed_when_dropped() {
+        let (producer, consumer) = channel();
+        assert!(!consumer.is_closed());
+        drop(producer);
+        assert!(consumer.is_closed());
+    }
+}
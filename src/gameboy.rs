@@ -0,0 +1,254 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{
+    audio::AudioSink,
+    cpu::Cpu,
+    error::Error,
+    frame::FrameProducer,
+    mem::Mmu,
+    ppu::Ppu,
+    state::{self, Snapshot, StateReader, StateWriter},
+    trace::TraceWriter,
+};
+
+// One frame is 154 lines of 456 cycles
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
+// Samples are sent to the audio sink in chunks of this size
+const AUDIO_CHUNK: usize = 1024;
+
+/// The whole machine, a cpu and everything it can reach through the memory bus
+pub struct Gameboy {
+    pub cpu: Cpu,
+    pub mmu: Mmu,
+    // Save state slots are stored next to it
+    rom_file: Option<String>,
+    audio: Option<Box<dyn AudioSink>>,
+    // One for each channel, in the order of `Channel::ALL`
+    channel_audio: Option<[Box<dyn AudioSink>; 4]>,
+    // Cycles run since the machine was started
+    cycles: u64,
+    trace: Option<TraceWriter>,
+}
+
+impl Gameboy {
+    pub fn new(mmu: Mmu, cpu: Cpu) -> Gameboy {
+        Gameboy {
+            cpu,
+            mmu,
+            rom_file: None,
+            audio: None,
+            channel_audio: None,
+            cycles: 0,
+            trace: None,
+        }
+    }
+
+    /// Loads the game rom and the boot rom, and starts the machine at the start of the boot rom
+    pub fn load(rom_file: &str, frames: FrameProducer) -> Result<Gameboy, Error> {
+        let ppu = Ppu::new(frames);
+        let mut mmu = Mmu::empty(ppu);
+        mmu.load_game_rom(rom_file)?;
+        mmu.load_boot_rom()?;
+
+        let mut gameboy = Gameboy::new(mmu, Cpu::default());
+        gameboy.rom_file = Some(rom_file.to_string());
+        Ok(gameboy)
+    }
+
+    /// The whole machine as a save state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.mmu.rom_checksum());
+        self.cpu.save_state(&mut state);
+        self.mmu.save_state(&mut state);
+        state.u64(self.cycles);
+        state.into_bytes()
+    }
+
+    /// The number of cycles run since the machine was started
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Restores a save state. If it can not be loaded the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let backup = self.save_state();
+        let res = self.restore_state(data);
+        if res.is_err() {
+            self.restore_state(&backup)
+                .expect("Restoring the state from before failed");
+        }
+        res
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut state = StateReader::new(data, self.mmu.rom_checksum())?;
+        self.cpu.load_state(&mut state)?;
+        self.mmu.load_state(&mut state)?;
+        // Version 1 did not save the cycle count
+        self.cycles = if state.version() >= 2 {
+            state.u64()?
+        } else {
+            0
+        };
+        state.finish()
+    }
+
+    /// Saves to a numbered slot, and returns the file it was saved to
+    pub fn save_slot(&self, slot: u8) -> Result<PathBuf, Error> {
+        let path = self.slot_path(slot)?;
+        fs::write(&path, self.save_state())?;
+        Ok(path)
+    }
+
+    pub fn load_slot(&mut self, slot: u8) -> Result<PathBuf, Error> {
+        let path = self.slot_path(slot)?;
+        self.load_state(&fs::read(&path)?)?;
+        Ok(path)
+    }
+
+    fn slot_path(&self, slot: u8) -> Result<PathBuf, Error> {
+        match &self.rom_file {
+            Some(rom_file) => Ok(state::slot_path(rom_file, slot)),
+            None => Err(Error::InvalidSaveState(
+                "no rom file to save next to".to_string(),
+            )),
+        }
+    }
+
+    /// Sends the sound to `sink`, sampled at `sample_rate`
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>, sample_rate: u32) {
+        self.mmu.apu.set_sample_rate(sample_rate);
+        self.audio = Some(sink);
+    }
+
+    /// Sends each sound channel on its own to a sink, in the order of `Channel::ALL`
+    pub fn start_channel_capture(&mut self, sinks: [Box<dyn AudioSink>; 4], sample_rate: u32) {
+        if self.mmu.apu.sample_rate() != sample_rate {
+            self.mmu.apu.set_sample_rate(sample_rate);
+        }
+        self.mmu.apu.set_channel_capture(true);
+        self.channel_audio = Some(sinks);
+    }
+
+    pub fn stop_channel_capture(&mut self) -> Result<(), Error> {
+        self.flush_audio()?;
+        if let Some(mut sinks) = self.channel_audio.take() {
+            for sink in sinks.iter_mut() {
+                sink.finish()?;
+            }
+        }
+        self.mmu.apu.set_channel_capture(false);
+        Ok(())
+    }
+
+    /// Logs every instruction before it runs
+    pub fn start_trace(&mut self, trace: TraceWriter) {
+        self.trace = Some(trace);
+    }
+
+    pub fn stop_trace(&mut self) -> Result<(), Error> {
+        if let Some(mut trace) = self.trace.take() {
+            trace.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the audio and the trace, before the emulator stops
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.finish_audio()?;
+        self.stop_trace()
+    }
+
+    /// Sends the last samples to the audio sinks and tells them we are done
+    pub fn finish_audio(&mut self) -> Result<(), Error> {
+        self.stop_channel_capture()?;
+        if let Some(sink) = &mut self.audio {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+
+    fn flush_audio(&mut self) -> Result<(), Error> {
+        let samples = self.mmu.apu.take_samples();
+        if let Some(sink) = &mut self.audio {
+            sink.write(&samples)?;
+        }
+        if let Some(sinks) = &mut self.channel_audio {
+            let channel_samples = self.mmu.apu.take_channel_samples();
+            for (sink, samples) in sinks.iter_mut().zip(channel_samples.iter()) {
+                sink.write(samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one instruction, and lets the rest of the hardware catch up
+    pub fn step(&mut self) -> Result<u32, Error> {
+        if let Some(mut trace) = self.trace.take() {
+            let res = trace.write(self);
+            self.trace = Some(trace);
+            res?;
+        }
+
+        let cycles = self.cpu.step(&mut self.mmu)?;
+        self.mmu.step(cycles)?;
+        self.cycles += cycles as u64;
+
+        if self.mmu.apu.sample_count() >= AUDIO_CHUNK {
+            self.flush_audio()?;
+        }
+
+        Ok(cycles)
+    }
+
+    /// Runs until the ppu has finished a frame. When the lcd is turned off
+    /// no frames are produced, so then we stop after the time one frame would take.
+    pub fn run_frame(&mut self) -> Result<(), Error> {
+        let frame = self.mmu.ppu.frame_count();
+        let mut cycles = 0;
+        while self.mmu.ppu.frame_count() == frame && cycles < CYCLES_PER_FRAME {
+            cycles += self.step()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame;
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let (frames, _consumer) = frame::channel();
+        let mut gameboy = Gameboy::new(Mmu::empty(Ppu::new(frames)), Cpu::default());
+        gameboy.cpu.a = 0x12;
+        gameboy.mmu.write_u8(0xc000, 0x34).unwrap();
+        gameboy.mmu.write_u8(0xff06, 0x56).unwrap();
+        gameboy.cycles = 1234;
+        let state = gameboy.save_state();
+        gameboy.cycles = 0;
+
+        gameboy.cpu.a = 0;
+        gameboy.mmu.write_u8(0xc000, 0).unwrap();
+        gameboy.mmu.write_u8(0xff06, 0).unwrap();
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.cpu.a, 0x12);
+        assert_eq!(gameboy.mmu.read_u8(0xc000).unwrap(), 0x34);
+        assert_eq!(gameboy.mmu.read_u8(0xff06).unwrap(), 0x56);
+        assert_eq!(gameboy.cycles(), 1234);
+
+        // Version 1 states have no cycle count
+        let mut old = state[..state.len() - 8].to_vec();
+        old[8..12].copy_from_slice(&1u32.to_le_bytes());
+        gameboy.load_state(&old).unwrap();
+        assert_eq!(gameboy.cycles(), 0);
+
+        // A broken state leaves the machine as it was
+        gameboy.cpu.a = 0x78;
+        assert!(gameboy.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(gameboy.cpu.a, 0x78);
+    }
+}
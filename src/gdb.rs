@@ -0,0 +1,463 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::{
+    debugger::{Debugger, Stop, WatchKind},
+    error::Error,
+    expr::Var,
+    mem::AccessKind,
+};
+
+/// The registers in the order gdb numbers them, as described by `TARGET_XML`
+const REGISTERS: [(Var, usize); 10] = [
+    (Var::A, 1),
+    (Var::F, 1),
+    (Var::B, 1),
+    (Var::C, 1),
+    (Var::D, 1),
+    (Var::E, 1),
+    (Var::H, 1),
+    (Var::L, 1),
+    (Var::SP, 2),
+    (Var::PC, 2),
+];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gbemu.sm83">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="f" bitsize="8" type="uint8"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="e" bitsize="8" type="uint8"/>
+    <reg name="h" bitsize="8" type="uint8"/>
+    <reg name="l" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Waits for gdb to connect to `addr`, and lets it control the debugger until it detaches
+pub fn listen(debugger: &mut Debugger, addr: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Waiting for gdb on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    println!("gdb connected from {}", peer);
+    serve(debugger, stream)
+}
+
+/// Speaks the gdb remote serial protocol on `stream` until gdb detaches or disconnects
+pub fn serve(debugger: &mut Debugger, stream: TcpStream) -> Result<(), Error> {
+    // Every ack and reply is a small write that gdb waits for
+    stream.set_nodelay(true)?;
+    let packets = read_packets(stream.try_clone()?, debugger);
+    let mut stream = stream;
+
+    // None is a packet with the wrong checksum, which gdb sends again
+    for packet in packets {
+        let packet = match packet {
+            Some(packet) => packet,
+            None => {
+                stream.write_all(b"-")?;
+                continue;
+            }
+        };
+        stream.write_all(b"+")?;
+
+        let (reply, done) = match handle(debugger, &packet) {
+            Command::Reply(reply) => (reply, false),
+            Command::Close(reply) => (reply, true),
+        };
+        send(&mut stream, &reply)?;
+        if done {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Reads packets in a thread, so a break (0x03) from gdb can stop the emulator while it runs
+fn read_packets(mut stream: TcpStream, debugger: &Debugger) -> Receiver<Option<String>> {
+    let interrupt = debugger.interrupt_flag();
+    let (packets, receiver) = channel();
+
+    thread::spawn(move || {
+        let mut byte = [0; 1];
+        let mut next = || match stream.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        };
+
+        while let Some(start) = next() {
+            match start {
+                0x03 => interrupt.store(true, Ordering::SeqCst),
+                b'$' => {
+                    let mut data = Vec::new();
+                    loop {
+                        match next() {
+                            Some(b'#') => break,
+                            Some(byte) => data.push(byte),
+                            None => return,
+                        }
+                    }
+                    let checksum = match (next(), next()) {
+                        (Some(high), Some(low)) => {
+                            u8::from_str_radix(&String::from_utf8_lossy(&[high, low]), 16).ok()
+                        }
+                        _ => return,
+                    };
+                    let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+                    let packet = match checksum {
+                        Some(checksum) if checksum == sum => {
+                            Some(String::from_utf8_lossy(&data).into_owned())
+                        }
+                        _ => None,
+                    };
+                    if packets.send(packet).is_err() {
+                        return;
+                    }
+                }
+                // Acks of our replies, we don't send again
+                _ => (),
+            }
+        }
+    });
+
+    receiver
+}
+
+fn send(stream: &mut TcpStream, data: &str) -> Result<(), Error> {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?;
+    Ok(())
+}
+
+enum Command {
+    Reply(String),
+    // The last reply before the connection is closed
+    Close(String),
+}
+
+fn handle(debugger: &mut Debugger, packet: &str) -> Command {
+    let reply = match packet.as_bytes().first() {
+        Some(b'?') => "S05".to_string(),
+        Some(b'g') => read_registers(debugger),
+        Some(b'G') => ok(write_registers(debugger, &packet[1..])),
+        Some(b'p') => read_register(debugger, &packet[1..]).unwrap_or_else(|| error(1)),
+        Some(b'P') => ok(write_register(debugger, &packet[1..])),
+        Some(b'm') => read_memory(debugger, &packet[1..]).unwrap_or_else(|| error(1)),
+        Some(b'M') => ok(write_memory(debugger, &packet[1..])),
+        Some(b'Z') => ok(set_point(debugger, &packet[1..], true)),
+        Some(b'z') => ok(set_point(debugger, &packet[1..], false)),
+        Some(b's') => {
+            jump(debugger, &packet[1..]);
+            stop_reply(debugger.step_instruction())
+        }
+        Some(b'c') => {
+            jump(debugger, &packet[1..]);
+            stop_reply(debugger.game_loop(|_, _| false))
+        }
+        Some(b'H') => "OK".to_string(),
+        Some(b'D') => return Command::Close("OK".to_string()),
+        Some(b'k') => return Command::Close(String::new()),
+        Some(b'q') => query(&packet[1..]),
+        // Anything else is not supported, which gdb knows how to handle
+        _ => String::new(),
+    };
+    Command::Reply(reply)
+}
+
+fn query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        "PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+".to_string()
+    } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        parse_range(range)
+            .map(|(offset, len)| {
+                let offset = (offset as usize).min(TARGET_XML.len());
+                let end = (offset + len).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                format!("{}{}", more, &TARGET_XML[offset..end])
+            })
+            .unwrap_or_else(|| error(1))
+    } else if query == "Attached" {
+        "1".to_string()
+    } else if query == "fThreadInfo" {
+        "m1".to_string()
+    } else if query == "sThreadInfo" {
+        "l".to_string()
+    } else {
+        String::new()
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Done => "S05".to_string(),
+        Stop::Breakpoint => "T05swbreak:;".to_string(),
+        Stop::Watchpoint(kind, access) => {
+            let name = match (kind, access.kind) {
+                (WatchKind::Access, _) => "awatch",
+                (_, AccessKind::Read) => "rwatch",
+                (_, AccessKind::Write) => "watch",
+            };
+            format!("T05{}:{:04x};", name, access.addr)
+        }
+        Stop::Interrupted => "S02".to_string(),
+        // Most likely an instruction we don't know, so an illegal instruction signal
+        Stop::Error => "S04".to_string(),
+    }
+}
+
+fn ok(res: Option<()>) -> String {
+    match res {
+        Some(()) => "OK".to_string(),
+        None => error(1),
+    }
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+// Continuing or stepping from somewhere else than pc
+fn jump(debugger: &mut Debugger, addr: &str) {
+    if let Ok(addr) = u16::from_str_radix(addr, 16) {
+        debugger.gameboy_mut().cpu.pc = addr;
+    }
+}
+
+fn read_registers(debugger: &Debugger) -> String {
+    REGISTERS
+        .iter()
+        .map(|&(var, size)| register_hex(debugger, var, size))
+        .collect()
+}
+
+// Little endian, like gdb wants it
+fn register_hex(debugger: &Debugger, var: Var, size: usize) -> String {
+    let value = var.value(debugger.gameboy());
+    (0..size)
+        .map(|i| format!("{:02x}", (value >> (8 * i)) as u8))
+        .collect()
+}
+
+fn read_register(debugger: &Debugger, index: &str) -> Option<String> {
+    let &(var, size) = REGISTERS.get(usize::from_str_radix(index, 16).ok()?)?;
+    Some(register_hex(debugger, var, size))
+}
+
+fn write_register(debugger: &mut Debugger, assignment: &str) -> Option<()> {
+    let mut parts = assignment.splitn(2, '=');
+    let index = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let &(var, size) = REGISTERS.get(index)?;
+    let bytes = decode_hex(parts.next()?)?;
+    set_register(debugger, var, &bytes[..size.min(bytes.len())])
+}
+
+fn write_registers(debugger: &mut Debugger, hex: &str) -> Option<()> {
+    let bytes = decode_hex(hex)?;
+    let mut rest = &bytes[..];
+    for &(var, size) in REGISTERS.iter() {
+        if rest.len() < size {
+            return None;
+        }
+        set_register(debugger, var, &rest[..size])?;
+        rest = &rest[size..];
+    }
+    Some(())
+}
+
+fn set_register(debugger: &mut Debugger, var: Var, bytes: &[u8]) -> Option<()> {
+    let value = bytes
+        .iter()
+        .rev()
+        .fold(0u64, |value, &byte| (value << 8) | byte as u64);
+    var.set(debugger.gameboy_mut(), value).ok()
+}
+
+fn read_memory(debugger: &Debugger, range: &str) -> Option<String> {
+    let (addr, len) = parse_range(range)?;
+    let mmu = &debugger.gameboy().mmu;
+    (0..len)
+        .map(|i| {
+            let byte = mmu.read_u8(addr.wrapping_add(i as u16)).ok()?;
+            Some(format!("{:02x}", byte))
+        })
+        .collect()
+}
+
+fn write_memory(debugger: &mut Debugger, args: &str) -> Option<()> {
+    let mut parts = args.splitn(2, ':');
+    let (addr, len) = parse_range(parts.next()?)?;
+    let bytes = decode_hex(parts.next()?)?;
+    if bytes.len() != len {
+        return None;
+    }
+    let mmu = &mut debugger.gameboy_mut().mmu;
+    for (i, &byte) in bytes.iter().enumerate() {
+        mmu.write_u8(addr.wrapping_add(i as u16), byte).ok()?;
+    }
+    Some(())
+}
+
+// Z and z: type,addr,kind. For watchpoints kind is the number of bytes.
+fn set_point(debugger: &mut Debugger, args: &str, insert: bool) -> Option<()> {
+    let mut parts = args.splitn(2, ',');
+    let kind = parts.next()?;
+    let (addr, len) = parse_range(parts.next()?)?;
+    let end = range_end(addr, len);
+
+    let watch = match kind {
+        // Software and hardware breakpoints are the same to us
+        "0" | "1" => {
+            if insert {
+                debugger
+                    .add_breakpoint(&format!("{:04x}", addr), &[])
+                    .ok()?;
+            } else {
+                debugger.remove_breakpoints_at(addr);
+            }
+            return Some(());
+        }
+        "2" => WatchKind::Write,
+        "3" => WatchKind::Read,
+        "4" => WatchKind::Access,
+        _ => return None,
+    };
+    if insert {
+        debugger.add_watchpoint((addr, end), watch, None);
+    } else {
+        debugger.remove_watchpoint((addr, end), watch);
+    }
+    Some(())
+}
+
+// addr,len in hex
+// The last address of a range, which stops at the end of memory rather than wrapping
+fn range_end(addr: u16, len: usize) -> u16 {
+    (addr as usize + len.max(1) - 1).min(0xffff) as u16
+}
+
+// Lengths are capped at the size of the address space
+fn parse_range(range: &str) -> Option<(u16, usize)> {
+    let mut parts = range.splitn(2, ',');
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    if len > 0x10000 {
+        return None;
+    }
+    Some((addr, len))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cpu::Cpu, gameboy::Gameboy, mem::Mmu};
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        // Sends a packet and returns the reply, after checking it was acked
+        fn request(&mut self, data: &str) -> String {
+            send(&mut self.stream, data).unwrap();
+            let mut byte = [0; 1];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+
+            let mut reply = Vec::new();
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_range_end() {
+        assert_eq!(range_end(0xc000, 0), 0xc000);
+        assert_eq!(range_end(0xc000, 2), 0xc001);
+        assert_eq!(range_end(0, 0x10000), 0xffff);
+        assert_eq!(range_end(0xc000, 0x10000), 0xffff);
+    }
+
+    #[test]
+    fn test_registers_memory_breakpoints_and_watchpoints() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            // ld (hl),a; inc a; jr -4
+            let mmu = Mmu::with_mem(vec![0x77, 0x3c, 0x18, 0xfc, 0x00, 0x00]);
+            let mut debugger = Debugger::new(Gameboy::new(mmu, Cpu::default()));
+            let (stream, _) = listener.accept().unwrap();
+            serve(&mut debugger, stream).unwrap();
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut gdb = Client { stream };
+
+        assert!(gdb
+            .request("qSupported:swbreak+")
+            .contains("qXfer:features:read+"));
+        let xml = gdb.request("qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml") && xml.contains("name=\"pc\""));
+        assert_eq!(gdb.request("?"), "S05");
+        assert_eq!(gdb.request("g"), "00".repeat(12));
+
+        assert_eq!(gdb.request("P0=3c"), "OK");
+        assert_eq!(gdb.request("P6=c0"), "OK");
+        assert_eq!(gdb.request("P8=feff"), "OK");
+        assert_eq!(gdb.request("p8"), "feff");
+        assert_eq!(gdb.request("m0,4"), "773c18fc");
+        assert_eq!(gdb.request("Mc000,2:abcd"), "OK");
+        assert_eq!(gdb.request("mc000,2"), "abcd");
+        assert_eq!(gdb.request("m0,ffffffff"), "E01");
+        assert_eq!(gdb.request("Z2,0,10001"), "E01");
+        assert_eq!(gdb.request("Z2,0,10000"), "OK");
+        assert_eq!(gdb.request("z2,0,10000"), "OK");
+
+        assert_eq!(gdb.request("Z0,2,1"), "OK");
+        assert_eq!(gdb.request("c"), "T05swbreak:;");
+        assert_eq!(gdb.request("p9"), "0200");
+        assert_eq!(gdb.request("p0"), "3d");
+        assert_eq!(gdb.request("z0,2,1"), "OK");
+        assert_eq!(gdb.request("s"), "S05");
+        assert_eq!(gdb.request("p9"), "0000");
+
+        // hl is c000, so the first instruction writes it
+        assert_eq!(gdb.request("Z2,c000,1"), "OK");
+        assert_eq!(gdb.request("c"), "T05watch:c000;");
+        assert_eq!(gdb.request("mc000,1"), "3d");
+        assert_eq!(gdb.request("z2,c000,1"), "OK");
+
+        assert_eq!(gdb.request("D"), "OK");
+        server.join().unwrap();
+    }
+}
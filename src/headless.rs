@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::{
+    error::Error,
+    frame::{self, FrameConsumer},
+    gameboy::Gameboy,
+    palette::Palette,
+    ppu::{SCREEN_HEIGHT, SCREEN_WIDTH},
+};
+
+/// A Game Boy without a window, for running roms in tests and on machines without a gpu
+pub struct Headless {
+    pub gameboy: Gameboy,
+    frames: FrameConsumer,
+    // Black until the lcd is turned on
+    framebuffer: Vec<u8>,
+}
+
+impl Headless {
+    pub fn load(rom_file: &str) -> Result<Headless, Error> {
+        let (producer, frames) = frame::channel();
+        let gameboy = Gameboy::load(rom_file, producer)?;
+
+        Ok(Headless {
+            gameboy,
+            frames,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+        })
+    }
+
+    pub fn run_frames(&mut self, frames: u32) -> Result<(), Error> {
+        for _ in 0..frames {
+            self.gameboy.run_frame()?;
+        }
+        Ok(())
+    }
+
+    /// The last finished frame as 160x144 RGB pixels (3 bytes per pixel, row by row)
+    pub fn framebuffer(&mut self) -> &[u8] {
+        if let Some(frame) = self.frames.latest() {
+            self.framebuffer.copy_from_slice(&frame.pixels);
+        }
+        &self.framebuffer
+    }
+}
+
+/// Runs a rom for the given number of frames without opening a window, and returns
+/// the last frame as 160x144 RGB pixels (3 bytes per pixel, row by row).
+pub fn run_frames(rom_file: &str, frames: u32, palette: Palette) -> Result<Vec<u8>, Error> {
+    let mut headless = Headless::load(rom_file)?;
+    headless.gameboy.mmu.ppu.set_palette(palette);
+    headless.run_frames(frames)?;
+    Ok(headless.framebuffer().to_vec())
+}
+
+/// Writes a RGB buffer to a png file
+pub fn save_png(filename: &str, width: u32, height: u32, rgb: &[u8]) -> Result<(), Error> {
+    let file = File::create(filename)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+    Ok(())
+}
+
+pub fn screenshot(headless: &mut Headless, frames: u32, output: &str) -> Result<(), Error> {
+    headless.run_frames(frames)?;
+    headless.gameboy.finish()?;
+    save_png(
+        output,
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+        headless.framebuffer(),
+    )?;
+    println!("Wrote frame {} to {}", frames, output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Without the logo in the header the boot rom stops with the lcd on and the screen blank
+    fn blank_rom(name: &str) -> String {
+        let rom_file = std::env::temp_dir().join(format!("gbemu-headless-{}.gb", name));
+        std::fs::write(&rom_file, vec![0; 0x8000]).unwrap();
+        rom_file.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_run_frames() {
+        let palette = Palette::classic_green();
+        let white = palette.rgb(0);
+        let pixels = run_frames(&blank_rom("run-frames"), 30, palette).unwrap();
+
+        assert_eq!(pixels.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        assert!(pixels.chunks(3).all(|pixel| pixel == white));
+    }
+
+    #[test]
+    fn test_short_rom() {
+        let rom_file = std::env::temp_dir().join("gbemu-headless-short.gb");
+        std::fs::write(&rom_file, vec![0; 0x100]).unwrap();
+        let res = Headless::load(rom_file.to_str().unwrap());
+        std::fs::remove_file(&rom_file).unwrap();
+        assert!(matches!(res, Err(Error::InvalidRom(_))));
+    }
+
+    #[test]
+    fn test_screenshot() {
+        let mut headless = Headless::load(&blank_rom("screenshot")).unwrap();
+        assert!(headless.framebuffer().iter().all(|&byte| byte == 0));
+
+        let output = std::env::temp_dir().join("gbemu-headless-test.png");
+        screenshot(&mut headless, 30, output.to_str().unwrap()).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&output).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut png = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut png).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        assert_eq!((info.width, info.height), (160, 144));
+        assert_eq!(&png[..info.buffer_size()], headless.framebuffer());
+    }
+}
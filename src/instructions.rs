@@ -1,7 +1,8 @@
 use std::fmt;
 
 use crate::error::Error;
-use crate::mem::Mmu;
+use crate::mem::ReadMem;
+use crate::symbols::{BankedAddr, Symbols};
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Instruction {
@@ -18,14 +19,19 @@ pub enum Instruction {
     Inc16 { loc: Loc16 },
     Dec8 { loc: Loc8 },
     Call { cond: Cond, addr: u16 },
+    // RST, a one byte call to one of 8 fixed addresses
+    Restart { addr: u16 },
     Return { cond: Cond },
     Push { loc: Loc16 },
     Pop { loc: Loc16 },
     // Other location is always A
     Compare { loc: Loc8 },
+    DisableInterrupts,
+    EnableInterrupts,
+    ReturnInterrupt,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum Cond {
     Always,
     NotCarry,
@@ -69,9 +75,22 @@ pub enum Loc16 {
     U16(u16),
 }
 
+impl Loc8 {
+    // Extra cycles needed to read or write the location, on top of the instruction itself
+    pub fn access_cycles(self) -> u32 {
+        match self {
+            Loc8::A | Loc8::B | Loc8::C | Loc8::D | Loc8::E | Loc8::H | Loc8::L => 0,
+            Loc8::IndHL | Loc8::IndBC | Loc8::IndDE | Loc8::IndHLDec | Loc8::IndHLInc => 4,
+            Loc8::U8(_) | Loc8::IOPlusC => 4,
+            Loc8::IOPlus(_) => 8,
+            Loc8::IndU16(_) => 12,
+        }
+    }
+}
+
 impl Instruction {
     // Returns the instruction and the number of bytes read
-    pub fn parse(pc: u16, mmu: &Mmu) -> Result<(Instruction, u16), Error> {
+    pub fn parse<M: ReadMem>(pc: u16, mmu: &M) -> Result<(Instruction, u16), Error> {
         match mmu.read_u8(pc)? {
             0x01 => Ok((
                 Instruction::Load16 {
@@ -272,7 +291,7 @@ impl Instruction {
             // This needs to be above the below check since HALT is in the middle of the load stuff
             // between 40 and 7f
             0x76 => Err(Error::TODOHalt),
-            inst @ 0x40...0x7f => {
+            inst @ 0x40..=0x7f => {
                 let high5 = inst & 0b11111000;
                 let low3 = inst & 0x07;
                 let src = match low3 {
@@ -300,7 +319,7 @@ impl Instruction {
 
                 Ok((Instruction::Load8 { src, dst }, 1))
             }
-            inst @ 0x80...0xbf => {
+            inst @ 0x80..=0xbf => {
                 let high5 = inst & 0b11111000;
                 let low3 = inst & 0x07;
                 let src = match low3 {
@@ -350,6 +369,13 @@ impl Instruction {
             0xd1 => Ok((Instruction::Pop { loc: Loc16::DE }, 1)),
             0xd5 => Ok((Instruction::Push { loc: Loc16::DE }, 1)),
             0xd8 => Ok((Instruction::Return { cond: Cond::Carry }, 1)),
+            0xd9 => Ok((Instruction::ReturnInterrupt, 1)),
+            inst @ (0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff) => Ok((
+                Instruction::Restart {
+                    addr: (inst & 0x38) as u16,
+                },
+                1,
+            )),
             0xe0 => Ok((
                 Instruction::Load8 {
                     src: Loc8::A,
@@ -380,6 +406,7 @@ impl Instruction {
                 },
                 2,
             )),
+            0xf3 => Ok((Instruction::DisableInterrupts, 1)),
             0xfa => Ok((
                 Instruction::Load8 {
                     src: Loc8::IndU16(mmu.read_u16(pc + 1)?),
@@ -387,6 +414,7 @@ impl Instruction {
                 },
                 3,
             )),
+            0xfb => Ok((Instruction::EnableInterrupts, 1)),
             0xfe => Ok((
                 Instruction::Compare {
                     loc: Loc8::U8(mmu.read_u8(pc + 1)?),
@@ -408,7 +436,7 @@ impl Instruction {
                     0x4 => H,
                     0x5 => L,
                     0x6 => IndHL,
-                    _ => panic!("This should not be possible"),
+                    _ => A,
                 };
 
                 let res = match high5 {
@@ -433,11 +461,80 @@ impl Instruction {
 }
 
 impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let disassembly = Disassembly {
+            inst: self,
+            pc: 0,
+            symbols: None,
+            rom_bank: 0,
+        };
+        write!(f, "{}", disassembly)
+    }
+}
+
+/// An instruction shown with labels in place of the addresses it uses and jumps to
+pub struct Disassembly<'a> {
+    inst: &'a Instruction,
+    // Where the instruction is, relative jumps are from here
+    pc: u16,
+    symbols: Option<&'a Symbols>,
+    rom_bank: u16,
+}
+
+impl Instruction {
+    pub fn with_symbols<'a>(
+        &'a self,
+        pc: u16,
+        symbols: &'a Symbols,
+        rom_bank: u16,
+    ) -> Disassembly<'a> {
+        Disassembly {
+            inst: self,
+            pc,
+            symbols: Some(symbols),
+            rom_bank,
+        }
+    }
+}
+
+impl Disassembly<'_> {
+    fn label(&self, addr: u16) -> Option<&str> {
+        self.symbols?.name(BankedAddr::new(addr, self.rom_bank))
+    }
+
+    fn addr(&self, addr: u16) -> String {
+        match self.label(addr) {
+            Some(name) => name.to_string(),
+            None => format!("${:04x}", addr),
+        }
+    }
+
+    fn loc8(&self, loc: Loc8) -> String {
+        let addr = match loc {
+            Loc8::IndU16(addr) => addr,
+            Loc8::IOPlus(offset) => 0xff00 + offset as u16,
+            _ => return loc.to_string(),
+        };
+        match self.label(addr) {
+            Some(name) => format!("({})", name),
+            None => loc.to_string(),
+        }
+    }
+
+    fn loc16(&self, loc: Loc16) -> String {
+        match loc {
+            Loc16::U16(addr) => self.addr(addr),
+            _ => loc.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Disassembly<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Instruction::*;
-        match self {
-            Load8 { dst, src } => write!(f, "LD {},{}", dst, src),
-            Load16 { dst, src } => write!(f, "LD {},{}", dst, src),
+        match *self.inst {
+            Load8 { dst, src } => write!(f, "LD {},{}", self.loc8(dst), self.loc8(src)),
+            Load16 { dst, src } => write!(f, "LD {},{}", self.loc16(dst), self.loc16(src)),
             XOR { dst, src } => write!(f, "XOR {},{}", dst, src),
             Sub { src } => write!(f, "SUB A,{}", src),
             AddA { src } => write!(f, "ADD A,{}", src),
@@ -448,11 +545,26 @@ impl fmt::Display for Instruction {
             CheckBit { bit, loc } => write!(f, "BIT {},{}", bit, loc),
             RotateLeftCarry { loc } => write!(f, "RLC {}", loc),
             RotateLeft { loc } => write!(f, "RL {}", loc),
-            JR { cond, offset } => write!(f, "JR {}${:02x}", cond, offset),
-            Call { cond, addr } => write!(f, "CALL {}${:04x}", cond, addr),
-            Return { cond } => write!(f, "RET {}", cond),
+            JR { cond, offset } => {
+                // The offset is from the end of the two byte instruction
+                let to = self.pc.wrapping_add(2).wrapping_add(offset as u16);
+                match self.label(to) {
+                    Some(name) => write!(f, "JR {}{}", cond, name),
+                    None => write!(f, "JR {}${:02x}", cond, offset),
+                }
+            }
+            Call { cond, addr } => write!(f, "CALL {}{}", cond, self.addr(addr)),
+            Restart { addr } => match self.label(addr) {
+                Some(name) => write!(f, "RST {}", name),
+                None => write!(f, "RST ${:02x}", addr),
+            },
+            Return { cond: Cond::Always } => write!(f, "RET"),
+            Return { cond } => write!(f, "RET {}", cond.name()),
             Push { loc } => write!(f, "PUSH {}", loc),
             Pop { loc } => write!(f, "POP {}", loc),
+            DisableInterrupts => write!(f, "DI"),
+            EnableInterrupts => write!(f, "EI"),
+            ReturnInterrupt => write!(f, "RETI"),
         }
     }
 }
@@ -492,14 +604,24 @@ impl fmt::Display for Loc16 {
     }
 }
 
+impl Cond {
+    fn name(self) -> &'static str {
+        match self {
+            Cond::NotZero => "NZ",
+            Cond::Zero => "Z",
+            Cond::NotCarry => "NC",
+            Cond::Carry => "C",
+            Cond::Always => "",
+        }
+    }
+}
+
+// With a comma after it, to go before the target of a jump or call
 impl fmt::Display for Cond {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Cond::NotZero => write!(f, "NZ,"),
-            Cond::Zero => write!(f, "Z,"),
-            Cond::NotCarry => write!(f, "NC,"),
-            Cond::Carry => write!(f, "C,"),
-            Cond::Always => write!(f, ""),
+            Cond::Always => Ok(()),
+            cond => write!(f, "{},", cond.name()),
         }
     }
 }
@@ -512,7 +634,7 @@ mod test {
     #[test]
     fn test_cb_c7() {
         let input = Mmu::with_mem(vec![0xcb, 0x7c]);
-        let (inst, delta) = Instruction::parse(0, &input);
+        let (inst, delta) = Instruction::parse(0, &input).unwrap();
         assert_eq!(delta, 2);
         assert_eq!(
             inst,
@@ -522,4 +644,56 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn test_cb_register_a() {
+        let input = Mmu::with_mem(vec![0xcb, 0x7f]);
+        let (inst, delta) = Instruction::parse(0, &input).unwrap();
+        assert_eq!(delta, 2);
+        assert_eq!(
+            inst,
+            Instruction::CheckBit {
+                bit: 7,
+                loc: Loc8::A
+            }
+        );
+    }
+
+    #[test]
+    fn test_disassembly_with_symbols() {
+        let symbols = Symbols::parse("00:0038 Reset38\n00:0150 Main\n00:ff80 hCounter\n").unwrap();
+        let call = Instruction::Call {
+            cond: Cond::Zero,
+            addr: 0x0150,
+        };
+        let load = Instruction::Load8 {
+            src: Loc8::A,
+            dst: Loc8::IOPlus(0x80),
+        };
+        assert_eq!(call.with_symbols(0, &symbols, 1).to_string(), "CALL Z,Main");
+        assert_eq!(
+            load.with_symbols(0, &symbols, 1).to_string(),
+            "LD (hCounter),A"
+        );
+        assert_eq!(load.to_string(), "LD (FF00+$80),A");
+
+        // Back to the start of a two byte jump at 0152
+        let jump = Instruction::JR {
+            cond: Cond::NotZero,
+            offset: -4,
+        };
+        assert_eq!(
+            jump.with_symbols(0x0152, &symbols, 1).to_string(),
+            "JR NZ,Main"
+        );
+        assert_eq!(jump.to_string(), "JR NZ,$fc");
+        let ret = |cond| Instruction::Return { cond }.to_string();
+        assert_eq!(ret(Cond::Zero), "RET Z");
+        assert_eq!(ret(Cond::Always), "RET");
+        let restart = Instruction::Restart { addr: 0x38 };
+        assert_eq!(
+            restart.with_symbols(0x0200, &symbols, 1).to_string(),
+            "RST Reset38"
+        );
+    }
 }
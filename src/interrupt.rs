@@ -0,0 +1,35 @@
+/// The five interrupt sources, in priority order. The value is the bit in IE and IF.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0,
+    LcdStat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+const ALL: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LcdStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+
+    /// Where the cpu jumps when the interrupt is serviced
+    pub fn handler(self) -> u16 {
+        0x40 + 8 * (self as u16)
+    }
+
+    /// The interrupt with the highest priority that is set in `pending`
+    pub fn highest_priority(pending: u8) -> Option<Interrupt> {
+        ALL.iter()
+            .cloned()
+            .find(|interrupt| pending & interrupt.mask() != 0)
+    }
+}
@@ -0,0 +1,154 @@
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    interrupt::Interrupt,
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // The direction buttons are the lower 4 bits, the action buttons the upper 4
+    fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+/// The P1 register (0xff00). The buttons are in a 2x4 matrix, and the game selects
+/// which row to read by writing 0 to bit 4 (directions) or bit 5 (actions).
+/// Pressed buttons read as 0.
+pub struct Joypad {
+    select: u8,
+    // One bit per button, 1 when pressed
+    pressed: u8,
+    interrupts: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad {
+            select: 0x30,
+            pressed: 0,
+            interrupts: 0,
+        }
+    }
+}
+
+impl Joypad {
+    pub fn read(&self) -> u8 {
+        0xc0 | self.select | self.lines()
+    }
+
+    pub fn write(&mut self, val: u8) {
+        let before = self.lines();
+        self.select = val & 0x30;
+        self.check_interrupt(before);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let before = self.lines();
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+        self.check_interrupt(before);
+    }
+
+    /// One bit per button, 1 when pressed. Directions are the lower 4 bits, actions the upper 4.
+    pub fn pressed(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn set_pressed(&mut self, pressed: u8) {
+        let before = self.lines();
+        self.pressed = pressed;
+        self.check_interrupt(before);
+    }
+
+    /// Interrupts requested since the last call
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::replace(&mut self.interrupts, 0)
+    }
+
+    // The lower 4 bits of P1
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0f;
+        if self.select & 0x10 == 0 {
+            lines &= !(self.pressed & 0x0f);
+        }
+        if self.select & 0x20 == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        lines
+    }
+
+    // The joypad interrupt is requested when any of the lines goes from high to low
+    fn check_interrupt(&mut self, before: u8) {
+        if before & !self.lines() != 0 {
+            self.interrupts |= Interrupt::Joypad.mask();
+        }
+    }
+}
+
+impl Snapshot for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.select);
+        state.u8(self.pressed);
+        state.u8(self.interrupts);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.select = state.u8()?;
+        self.pressed = state.u8()?;
+        self.interrupts = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select_rows() {
+        let mut joypad = Joypad::default();
+        joypad.set_button(Button::Down, true);
+        joypad.set_button(Button::A, true);
+
+        // Nothing selected
+        assert_eq!(joypad.read(), 0xff);
+
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xe7);
+
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xde);
+    }
+
+    #[test]
+    fn test_interrupt_on_press() {
+        let mut joypad = Joypad::default();
+        joypad.set_button(Button::Start, true);
+        // Actions not selected, so the line does not change
+        assert_eq!(joypad.take_interrupts(), 0);
+
+        joypad.set_button(Button::Start, false);
+        joypad.write(0x10);
+        joypad.set_button(Button::Start, true);
+        assert_eq!(joypad.take_interrupts(), Interrupt::Joypad.mask());
+        assert_eq!(joypad.take_interrupts(), 0);
+    }
+}
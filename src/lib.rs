@@ -0,0 +1,28 @@
+pub mod apu;
+pub mod audio;
+pub mod config;
+pub mod cpu;
+pub mod debugger;
+pub mod display;
+pub mod error;
+pub mod expr;
+pub mod frame;
+pub mod gameboy;
+pub mod gdb;
+pub mod headless;
+pub mod instructions;
+pub mod interrupt;
+pub mod joypad;
+pub mod link;
+pub mod mem;
+pub mod movie;
+pub mod pacing;
+pub mod palette;
+pub mod ppu;
+pub mod printer;
+pub mod rewind;
+pub mod serial;
+pub mod state;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
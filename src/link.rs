@@ -0,0 +1,226 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// The other end of the link cable.
+///
+/// The side using the internal clock drives the transfer: it sends its byte and waits
+/// until the other side has answered with the byte in its SB. This keeps the two
+/// machines in step at every transfer without syncing them cycle by cycle.
+pub trait Link: Send {
+    /// Sends a byte with our clock, and returns the byte the other side had in SB
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// If the other side has started a transfer, answers with `reply` and returns its byte
+    fn poll(&mut self, reply: u8) -> Option<u8>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    Transfer(u8),
+    Reply(u8),
+}
+
+// A paused or stuck peer (e.g. at a debugger prompt) must not hang us, so after this long
+// we act as if nothing is connected
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// One end of a link cable made from two channels
+pub struct ChannelLink {
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+    timeout: Duration,
+}
+
+/// Two connected ends, for linking two Game Boys in the same process.
+/// Each of them must run on its own thread, as `exchange` waits for the other side.
+pub fn pair() -> (ChannelLink, ChannelLink) {
+    let (tx_a, rx_b) = mpsc::channel();
+    let (tx_b, rx_a) = mpsc::channel();
+    (
+        ChannelLink {
+            tx: tx_a,
+            rx: rx_a,
+            timeout: REPLY_TIMEOUT,
+        },
+        ChannelLink {
+            tx: tx_b,
+            rx: rx_b,
+            timeout: REPLY_TIMEOUT,
+        },
+    )
+}
+
+impl Link for ChannelLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        if self.tx.send(Message::Transfer(byte)).is_err() {
+            // Disconnected, reads as nothing connected
+            return 0xff;
+        }
+        loop {
+            match self.rx.recv_timeout(self.timeout) {
+                Ok(Message::Reply(reply)) => return reply,
+                // Both sides started a transfer with their own clock at the same time.
+                // Let the other side have our byte so neither waits forever.
+                Ok(Message::Transfer(_)) => {
+                    self.tx.send(Message::Reply(byte)).ok();
+                }
+                // The reply may still come, `poll` throws it away
+                Err(RecvTimeoutError::Timeout) => return 0xff,
+                Err(RecvTimeoutError::Disconnected) => return 0xff,
+            }
+        }
+    }
+
+    fn poll(&mut self, reply: u8) -> Option<u8> {
+        match self.rx.try_recv() {
+            Ok(Message::Transfer(byte)) => {
+                self.tx.send(Message::Reply(reply)).ok();
+                Some(byte)
+            }
+            // A late reply to a transfer we already gave up on
+            Ok(Message::Reply(_)) | Err(_) => None,
+        }
+    }
+}
+
+/// Waits for another instance to connect with `connect_tcp`
+pub fn listen_tcp(addr: impl ToSocketAddrs) -> Result<ChannelLink, Error> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    tcp_link(stream)
+}
+
+pub fn connect_tcp(addr: impl ToSocketAddrs) -> Result<ChannelLink, Error> {
+    tcp_link(TcpStream::connect(addr)?)
+}
+
+// Each message is two bytes on the wire, the kind and the value. Two threads move
+// messages between the socket and the channels, so polling never touches the socket.
+fn tcp_link(stream: TcpStream) -> Result<ChannelLink, Error> {
+    stream.set_nodelay(true)?;
+    let (local, remote) = pair();
+    let ChannelLink { tx, rx, .. } = remote;
+
+    let mut reader = stream.try_clone()?;
+    thread::spawn(move || {
+        let mut buf = [0; 2];
+        while reader.read_exact(&mut buf).is_ok() {
+            let message = match buf[0] {
+                0 => Message::Transfer(buf[1]),
+                _ => Message::Reply(buf[1]),
+            };
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut writer = stream;
+    thread::spawn(move || {
+        for message in rx {
+            let buf = match message {
+                Message::Transfer(byte) => [0, byte],
+                Message::Reply(byte) => [1, byte],
+            };
+            if writer.write_all(&buf).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(local)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serial::Serial;
+
+    // Runs one transfer between two serial ports, returns what ended up in their SB
+    fn transfer(mut master: Serial, mut slave: Serial) -> (u8, u8) {
+        let slave = thread::spawn(move || {
+            while slave.take_interrupts() == 0 {
+                slave.step(4);
+            }
+            slave.read_register(0xff01)
+        });
+
+        master.write_register(0xff02, 0x81);
+        master.step(8 * 512);
+        assert_ne!(master.take_interrupts(), 0);
+        (master.read_register(0xff01), slave.join().unwrap())
+    }
+
+    #[test]
+    fn test_in_process_link() {
+        let (a, b) = pair();
+        let mut master = Serial::default();
+        master.set_link(Box::new(a));
+        master.write_register(0xff01, 0x11);
+        let mut slave = Serial::default();
+        slave.set_link(Box::new(b));
+        slave.write_register(0xff01, 0x42);
+        slave.write_register(0xff02, 0x80);
+
+        assert_eq!(transfer(master, slave), (0x42, 0x11));
+    }
+
+    #[test]
+    fn test_exchange_times_out() {
+        let (mut a, mut b) = pair();
+        a.timeout = Duration::from_millis(10);
+        assert_eq!(a.exchange(0x12), 0xff);
+
+        // The other side answers too late, which must not be taken for a transfer
+        assert_eq!(b.poll(0x34), Some(0x12));
+        assert_eq!(a.poll(0x56), None);
+    }
+
+    #[test]
+    fn test_slave_only_shifts_when_requested() {
+        let (a, b) = pair();
+        let mut master = Serial::default();
+        master.set_link(Box::new(a));
+        master.write_register(0xff01, 0x11);
+        let mut slave = Serial::default();
+        slave.set_link(Box::new(b));
+        slave.write_register(0xff01, 0x42);
+
+        // Nothing is written to the slave's SC, so it has not asked for a transfer
+        let master = thread::spawn(move || {
+            master.write_register(0xff02, 0x81);
+            master.step(8 * 512);
+            master.read_register(0xff01)
+        });
+        while !master.is_finished() {
+            slave.step(512);
+        }
+        assert_eq!(master.join().unwrap(), 0x42);
+        assert_eq!(slave.read_register(0xff01), 0x42);
+        assert_eq!(slave.take_interrupts(), 0);
+    }
+
+    #[test]
+    fn test_tcp_link() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || connect_tcp(addr).unwrap());
+        let a = tcp_link(listener.accept().unwrap().0).unwrap();
+        let b = client.join().unwrap();
+
+        let mut master = Serial::default();
+        master.set_link(Box::new(a));
+        master.write_register(0xff01, 0xab);
+        let mut slave = Serial::default();
+        slave.set_link(Box::new(b));
+        slave.write_register(0xff01, 0xcd);
+        slave.write_register(0xff02, 0x80);
+
+        assert_eq!(transfer(master, slave), (0xcd, 0xab));
+    }
+}
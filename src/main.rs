@@ -1,23 +1,34 @@
-mod cpu;
-mod debugger;
-mod display;
-mod error;
-mod instructions;
-mod mem;
-mod ppu;
-
 use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 
-use parking_lot::Mutex;
 use structopt::StructOpt;
 
-use cpu::Cpu;
-use debugger::Debugger;
-use instructions::Instruction;
-use mem::Mmu;
-use ppu::Ppu;
+use gbemu::{
+    apu::Channel,
+    audio::{self, AudioSink, WavWriter},
+    config::Config,
+    debugger::Debugger,
+    display::{self, Command, KeyBindings},
+    frame,
+    gameboy::Gameboy,
+    headless::{self, Headless},
+    instructions::Instruction,
+    link,
+    mem::{Mmu, BOOT_ROM_FILE},
+    movie::{Movie, MovieSession},
+    pacing::{self, FramePacer},
+    palette::PaletteList,
+    ppu::Ppu,
+    printer::Printer,
+    rewind::Rewind,
+    symbols::{BankedAddr, Symbols},
+    trace::{self, TraceFormat, TraceWriter},
+};
 
 /// A basic example
 #[derive(StructOpt, Debug)]
@@ -26,9 +37,274 @@ enum Opt {
     #[structopt(name = "disassemble_bootrom")]
     DisassembleBootrom,
     #[structopt(name = "run")]
-    Run { rom_file: String },
+    Run {
+        #[structopt(flatten)]
+        machine: MachineOpt,
+        /// Speed multiplier, 2 is twice as fast as a real Game Boy
+        #[structopt(
+            long = "speed",
+            default_value = "1",
+            parse(try_from_str = "pacing::parse_speed")
+        )]
+        speed: f64,
+        /// Run as fast as possible
+        #[structopt(long = "uncapped")]
+        uncapped: bool,
+        /// Memory for the rewind history in MB, 0 turns rewinding off
+        #[structopt(long = "rewind-mb", default_value = "64")]
+        rewind_mb: usize,
+        /// Frames between each rewind snapshot
+        #[structopt(long = "rewind-interval", default_value = "2")]
+        rewind_interval: u32,
+        /// Record the input of every frame to a movie file
+        #[structopt(long = "record")]
+        record: Option<String>,
+        /// Play back a movie recorded with --record
+        #[structopt(long = "replay")]
+        replay: Option<String>,
+    },
     #[structopt(name = "debug")]
-    Debug { rom_file: String },
+    Debug {
+        #[structopt(flatten)]
+        machine: MachineOpt,
+        /// Wait for gdb to connect on this address, e.g. 127.0.0.1:2345, instead of the prompt
+        #[structopt(long = "gdb")]
+        gdb: Option<String>,
+        /// Run the debugger commands in this file before the prompt. End it with quit to exit.
+        #[structopt(long = "script")]
+        script: Option<String>,
+        /// Debugger commands to run at the start, defaults to gbemu.init if it exists
+        #[structopt(long = "init")]
+        init: Option<String>,
+        /// Don't run an init file
+        #[structopt(long = "no-init")]
+        no_init: bool,
+        /// Where to keep the history of the prompt
+        #[structopt(long = "history", default_value = "history.txt")]
+        history: String,
+    },
+    /// Run a rom without a window and save the last frame as a png
+    #[structopt(name = "screenshot")]
+    Screenshot {
+        #[structopt(flatten)]
+        machine: MachineOpt,
+        /// Number of frames to run before taking the screenshot
+        #[structopt(long = "frames", default_value = "60")]
+        frames: u32,
+        #[structopt(long = "output", short = "o", default_value = "screenshot.png")]
+        output: String,
+    },
+    /// Run alongside a trace in the gameboy-doctor format and stop where we differ from it
+    #[structopt(name = "trace_diff")]
+    TraceDiff {
+        #[structopt(flatten)]
+        machine: MachineOpt,
+        reference: String,
+        /// Number of instructions to show before the difference
+        #[structopt(long = "context", default_value = "10")]
+        context: usize,
+    },
+}
+
+/// What to run and what is connected to it
+#[derive(StructOpt, Debug)]
+struct MachineOpt {
+    rom_file: String,
+    #[structopt(flatten)]
+    display: DisplayOpt,
+    #[structopt(flatten)]
+    audio: AudioOpt,
+    #[structopt(flatten)]
+    serial: SerialOpt,
+    /// Start from a save state slot (1-9), saved next to the rom
+    #[structopt(long = "load-slot")]
+    load_slot: Option<u8>,
+    /// Log every instruction to a file
+    #[structopt(long = "trace")]
+    trace: Option<String>,
+    /// doctor for the gameboy-doctor format, or rich for cycles and disassembly
+    #[structopt(long = "trace-format", default_value = "doctor")]
+    trace_format: TraceFormat,
+}
+
+impl MachineOpt {
+    fn apply(&self, gameboy: &mut Gameboy) -> Result<(), gbemu::error::Error> {
+        self.audio.apply(gameboy)?;
+        self.serial.apply(gameboy)?;
+        if let Some(slot) = self.load_slot {
+            let path = gameboy.load_slot(slot)?;
+            println!("Loaded state from {}", path.display());
+        }
+        if let Some(filename) = &self.trace {
+            let mut trace = TraceWriter::create(filename, self.trace_format)?;
+            trace.set_symbols(Symbols::for_rom(&self.rom_file)?);
+            gameboy.start_trace(trace);
+        }
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct DisplayOpt {
+    /// Config file, defaults to gbemu.toml if it exists
+    #[structopt(long = "config")]
+    config: Option<String>,
+    /// Name of the palette to start with
+    #[structopt(long = "palette")]
+    palette: Option<String>,
+}
+
+impl DisplayOpt {
+    fn config(&self) -> Result<Config, gbemu::error::Error> {
+        Config::load(self.config.as_deref())
+    }
+
+    fn palettes(&self, config: &Config) -> Result<PaletteList, gbemu::error::Error> {
+        let selected = self.palette.as_deref().or(config.palette.as_deref());
+        PaletteList::new(config.palettes()?, selected)
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct AudioOpt {
+    /// Write the sound to a .wav file
+    #[structopt(long = "audio-out")]
+    audio_out: Option<String>,
+    /// Play the sound on the default output device
+    #[structopt(long = "live-audio")]
+    live_audio: bool,
+    #[structopt(long = "sample-rate", default_value = "44100")]
+    sample_rate: u32,
+    /// Write each sound channel to its own .wav file, named <prefix>-<channel>.wav
+    #[structopt(long = "channel-out")]
+    channel_out: Option<String>,
+    /// Mute a sound channel (square1, square2, wave, noise or 1-4), can be repeated
+    #[structopt(long = "mute", raw(number_of_values = "1"))]
+    mute: Vec<Channel>,
+    /// Only play this sound channel, can be repeated
+    #[structopt(long = "solo", raw(number_of_values = "1"))]
+    solo: Vec<Channel>,
+}
+
+impl AudioOpt {
+    /// Connects the chosen audio sink, the apu does not make samples if there is none
+    fn apply(&self, gameboy: &mut Gameboy) -> Result<(), gbemu::error::Error> {
+        for &channel in &self.mute {
+            gameboy.mmu.apu.set_muted(channel, true);
+        }
+        for &channel in &self.solo {
+            gameboy.mmu.apu.set_solo(channel, true);
+        }
+        if let Some(prefix) = &self.channel_out {
+            let sinks = audio::channel_wav_writers(prefix, self.sample_rate)?;
+            gameboy.start_channel_capture(sinks, self.sample_rate);
+        }
+
+        let sink: Box<dyn AudioSink> = if let Some(filename) = &self.audio_out {
+            Box::new(WavWriter::create(filename, self.sample_rate)?)
+        } else if self.live_audio {
+            live_sink(self.sample_rate)?
+        } else {
+            return Ok(());
+        };
+        gameboy.set_audio_sink(sink, self.sample_rate);
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct SerialOpt {
+    /// Print the bytes sent over the serial port, test roms report their results this way
+    #[structopt(long = "serial-stdout")]
+    serial_stdout: bool,
+    /// Wait for another gbemu to connect a link cable, e.g. 127.0.0.1:8765
+    #[structopt(long = "link-listen")]
+    link_listen: Option<String>,
+    /// Connect a link cable to another gbemu started with --link-listen
+    #[structopt(long = "link-connect", conflicts_with = "link_listen")]
+    link_connect: Option<String>,
+    /// Connect a Game Boy Printer, prints are saved as <prefix>-<n>.png
+    #[structopt(
+        long = "printer",
+        raw(conflicts_with_all = r#"&["link_listen", "link_connect"]"#)
+    )]
+    printer: Option<String>,
+}
+
+impl SerialOpt {
+    fn apply(&self, gameboy: &mut Gameboy) -> Result<(), gbemu::error::Error> {
+        gameboy.mmu.serial.set_console(self.serial_stdout);
+
+        if let Some(addr) = &self.link_listen {
+            println!("Waiting for link cable on {}", addr);
+            gameboy
+                .mmu
+                .serial
+                .set_link(Box::new(link::listen_tcp(addr)?));
+            println!("Link cable connected");
+        } else if let Some(addr) = &self.link_connect {
+            gameboy
+                .mmu
+                .serial
+                .set_link(Box::new(link::connect_tcp(addr)?));
+            println!("Link cable connected");
+        } else if let Some(prefix) = &self.printer {
+            gameboy.mmu.serial.set_link(Box::new(Printer::new(prefix)));
+        }
+        Ok(())
+    }
+}
+
+struct MovieOpt {
+    record: Option<String>,
+    replay: Option<String>,
+}
+
+struct PromptOpt {
+    script: Option<String>,
+    init: Option<String>,
+    no_init: bool,
+    history: String,
+}
+
+impl PromptOpt {
+    /// The init file followed by the script
+    fn scripts(&self) -> Vec<PathBuf> {
+        let init = if self.no_init {
+            None
+        } else {
+            Debugger::init_file(self.init.as_deref())
+        };
+        init.into_iter()
+            .chain(self.script.iter().map(PathBuf::from))
+            .collect()
+    }
+}
+
+impl MovieOpt {
+    fn session(&self, gameboy: &mut Gameboy) -> Result<MovieSession, gbemu::error::Error> {
+        if let Some(filename) = &self.replay {
+            let movie = Movie::load(filename)?;
+            println!("Playing {} frames from {}", movie.frames(), filename);
+            MovieSession::replay(gameboy, movie)
+        } else if let Some(filename) = &self.record {
+            Ok(MovieSession::record(gameboy, filename))
+        } else {
+            Ok(MovieSession::Off)
+        }
+    }
+}
+
+#[cfg(feature = "live-audio")]
+fn live_sink(sample_rate: u32) -> Result<Box<dyn AudioSink>, gbemu::error::Error> {
+    Ok(Box::new(gbemu::audio::LiveSink::new(sample_rate)?))
+}
+
+#[cfg(not(feature = "live-audio"))]
+fn live_sink(_sample_rate: u32) -> Result<Box<dyn AudioSink>, gbemu::error::Error> {
+    Err(gbemu::error::Error::AudioError(
+        "gbemu was built without the live-audio feature".to_string(),
+    ))
 }
 
 fn main() {
@@ -37,53 +313,190 @@ fn main() {
     }
 }
 
-fn main_() -> Result<(), Box<Error>> {
+fn main_() -> Result<(), Box<dyn Error>> {
     let matches = Opt::from_args();
 
     match matches {
         Opt::DisassembleBootrom => disassemble_bootrom(),
-        Opt::Run { rom_file } => run(&rom_file),
-        Opt::Debug { rom_file } => debug(&rom_file),
+        Opt::Run {
+            machine,
+            speed,
+            uncapped,
+            rewind_mb,
+            rewind_interval,
+            record,
+            replay,
+        } => {
+            let rewind = if rewind_mb > 0 {
+                Some(Rewind::new(rewind_interval, rewind_mb * 1024 * 1024))
+            } else {
+                None
+            };
+            let movie = MovieOpt { record, replay };
+            run(&machine, FramePacer::new(speed, uncapped), rewind, &movie)
+        }
+        Opt::Debug {
+            machine,
+            gdb,
+            script,
+            init,
+            no_init,
+            history,
+        } => {
+            let prompt = PromptOpt {
+                script,
+                init,
+                no_init,
+                history,
+            };
+            debug(&machine, gdb.as_deref(), &prompt)
+        }
+        Opt::Screenshot {
+            machine,
+            frames,
+            output,
+        } => {
+            let config = machine.display.config()?;
+            let palette = machine.display.palettes(&config)?.current().clone();
+            let mut headless = Headless::load(&machine.rom_file)?;
+            headless.gameboy.mmu.ppu.set_palette(palette);
+            machine.apply(&mut headless.gameboy)?;
+            Ok(headless::screenshot(&mut headless, frames, &output)?)
+        }
+        Opt::TraceDiff {
+            machine,
+            reference,
+            context,
+        } => trace_diff(&machine, &reference, context),
     }
 }
 
-fn debug(rom_file: &str) -> Result<(), Box<Error>> {
-    let (display_thread, display) = display::start_thread();
-    let ppu = Ppu::new(display);
-    let mut mmu = Mmu::empty(ppu);
-    mmu.load_game_rom(rom_file)?;
-    mmu.load_boot_rom()?;
-    let cpu = Cpu::default();
+fn trace_diff(machine: &MachineOpt, reference: &str, context: usize) -> Result<(), Box<dyn Error>> {
+    let mut headless = Headless::load(&machine.rom_file)?;
+    machine.apply(&mut headless.gameboy)?;
+    let gameboy = &mut headless.gameboy;
+
+    let symbols = Symbols::for_rom(&machine.rom_file)?;
+    let reference = BufReader::new(File::open(reference)?);
+    let res = trace::diff(gameboy, reference, context, &symbols);
+    gameboy.finish()?;
 
-    Debugger::new(mmu, cpu).run()?;
+    let divergence = match res? {
+        Some(divergence) => divergence,
+        None => {
+            println!("No differences from the reference");
+            return Ok(());
+        }
+    };
 
+    for (line, inst) in &divergence.context {
+        println!("  {}    {}", line, inst);
+    }
+    println!(
+        "Differs at line {} of the reference, in {}:",
+        divergence.line,
+        divergence.fields.join(", ")
+    );
+    println!("  expected {}", divergence.expected);
+    println!("  actual   {}", divergence.actual);
+    if let Some((_, inst)) = divergence.context.last() {
+        println!("after running {}", inst);
+    }
+    let pc = gameboy.cpu.pc;
+    match Instruction::parse(pc, &gameboy.mmu) {
+        Ok((inst, _)) => println!(
+            "next is {}",
+            inst.with_symbols(pc, &symbols, gameboy.mmu.rom_bank())
+        ),
+        Err(err) => println!("next is unknown: {}", err),
+    }
     Ok(())
 }
 
-fn run(rom_file: &str) -> Result<(), Box<Error>> {
-    let (display_thread, display) = display::start_thread();
+fn debug(
+    machine: &MachineOpt,
+    gdb: Option<&str>,
+    prompt: &PromptOpt,
+) -> Result<(), Box<dyn Error>> {
+    let config = machine.display.config()?;
+    let palettes = machine.display.palettes(&config)?;
+    let bindings = KeyBindings::with_overrides(&config.keys)?;
+    let (_display_thread, display, commands) = display::start_thread(bindings);
+    let mut gameboy = Gameboy::load(&machine.rom_file, display)?;
+    gameboy.mmu.ppu.set_palette(palettes.current().clone());
+    machine.apply(&mut gameboy)?;
 
-    let mut ppu = Ppu::new(display);
-    let mut mmu = Mmu::empty(ppu);
-    mmu.load_game_rom(rom_file)?;
-    mmu.load_boot_rom()?;
-    let mut cpu = Cpu::default();
+    let mut debugger = Debugger::new(gameboy);
+    debugger.set_commands(commands, palettes);
+    let symbols = Symbols::for_rom(&machine.rom_file)?;
+    if !symbols.is_empty() {
+        println!(
+            "Loaded {} symbols from {}",
+            symbols.len(),
+            Symbols::path_for_rom(&machine.rom_file).display()
+        );
+        debugger.set_symbols(symbols);
+    }
+    match gdb {
+        Some(addr) => debugger.serve_gdb(addr)?,
+        None => {
+            debugger.set_history_file(PathBuf::from(&prompt.history));
+            debugger.run(&prompt.scripts())?
+        }
+    }
+
+    Ok(())
+}
+
+fn run(
+    machine: &MachineOpt,
+    mut pacer: FramePacer,
+    rewind: Option<Rewind>,
+    movie_opt: &MovieOpt,
+) -> Result<(), Box<dyn Error>> {
+    let config = machine.display.config()?;
+    let mut palettes = machine.display.palettes(&config)?;
+    let bindings = KeyBindings::with_overrides(&config.keys)?;
+    let (display_thread, display, commands) = display::start_thread(bindings);
+    let mut gameboy = Gameboy::load(&machine.rom_file, display)?;
+    gameboy.mmu.ppu.set_palette(palettes.current().clone());
+    machine.apply(&mut gameboy)?;
+    let mut movie = movie_opt.session(&mut gameboy)?;
 
-    if let Err(err) = game_loop(&mut cpu, &mut mmu) {
+    if let Err(err) = game_loop(
+        &mut gameboy,
+        &commands,
+        &mut palettes,
+        &mut pacer,
+        rewind,
+        &mut movie,
+    ) {
         println!(
             "----\nExecution stopped while running simulation:\n{}\n\nDumping memory to memdump.hex",
             err
         );
-        println!("Registers:\n{}", cpu);
-        mmu.dump_to_file("memdump.hex")?;
+        println!("Registers:\n{}", gameboy.cpu);
+        gameboy.mmu.dump_to_file("memdump.hex")?;
     }
+    movie.finish()?;
+    gameboy.finish()?;
 
+    // Closes the window
+    drop(gameboy);
     display_thread.join().unwrap();
 
     Ok(())
 }
 
-fn game_loop(cpu: &mut Cpu, mmu: &mut Mmu) -> Result<(), Box<Error>> {
+fn game_loop(
+    gameboy: &mut Gameboy,
+    commands: &Receiver<Command>,
+    palettes: &mut PaletteList,
+    pacer: &mut FramePacer,
+    mut rewind: Option<Rewind>,
+    movie: &mut MovieSession,
+) -> Result<(), Box<dyn Error>> {
+    let mut rewinding = false;
     let interrupt = Arc::new(AtomicBool::new(false));
     ctrlc::set_handler({
         let interrupt = interrupt.clone();
@@ -93,22 +506,85 @@ fn game_loop(cpu: &mut Cpu, mmu: &mut Mmu) -> Result<(), Box<Error>> {
     })?;
 
     loop {
-        cpu.print_next(mmu)?;
-        cpu.step(mmu)?;
-        mmu.ppu.step();
+        movie.before_frame(gameboy);
 
+        match &mut rewind {
+            Some(history) if rewinding => {
+                history.step_back(gameboy)?;
+                // Run the frame to have something to show, the next step back undoes it
+                gameboy.run_frame()?;
+            }
+            Some(history) => {
+                gameboy.run_frame()?;
+                history.record(gameboy);
+            }
+            None => gameboy.run_frame()?,
+        }
+
+        if gameboy.mmu.ppu.display_closed() {
+            return Ok(());
+        }
         if interrupt.load(Ordering::Relaxed) {
-            return Err(crate::error::Error::Abort("Interrupt").into());
+            return Err(gbemu::error::Error::Abort("Interrupt").into());
         }
+
+        for command in commands.try_iter() {
+            match command {
+                Command::Button(button, pressed) => {
+                    if !movie.is_replaying() {
+                        gameboy.mmu.joypad.set_button(button, pressed);
+                    }
+                    continue;
+                }
+                Command::LoadState(_) | Command::Rewind(true) if !movie.allows_state_changes() => {
+                    println!("Can not change the state while recording or playing a movie");
+                    continue;
+                }
+                Command::CyclePalette => {
+                    let palette = palettes.cycle();
+                    println!("Palette: {}", palette.name);
+                    gameboy.mmu.ppu.set_palette(palette.clone());
+                    continue;
+                }
+                Command::SaveState(slot) => {
+                    match gameboy.save_slot(slot) {
+                        Ok(path) => println!("Saved state to {}", path.display()),
+                        Err(err) => println!("Saving state failed: {}", err),
+                    }
+                    continue;
+                }
+                Command::LoadState(slot) => {
+                    match gameboy.load_slot(slot) {
+                        Ok(path) => println!("Loaded state from {}", path.display()),
+                        Err(err) => println!("Loading state failed: {}", err),
+                    }
+                    continue;
+                }
+                Command::Rewind(pressed) => {
+                    rewinding = pressed;
+                    continue;
+                }
+                Command::SpeedUp => pacer.speed_up(),
+                Command::SlowDown => pacer.slow_down(),
+                Command::ResetSpeed => pacer.reset_speed(),
+                Command::ToggleUncapped => pacer.toggle_uncapped(),
+            }
+            println!("Speed: {}", pacer);
+        }
+
+        pacer.wait();
     }
 }
 
-fn disassemble_bootrom() -> Result<(), Box<Error>> {
+fn disassemble_bootrom() -> Result<(), Box<dyn Error>> {
     // Will never run ppu
-    let mut ppu = Ppu::new(Arc::new(Mutex::new(vec![])));
+    let (frames, _) = frame::channel();
+    let ppu = Ppu::new(frames);
     let mut mmu = Mmu::empty(ppu);
     mmu.load_boot_rom()?;
     let mmu = mmu;
+    // Labels from roms/DMG_ROM.sym, if it is there
+    let symbols = Symbols::for_rom(BOOT_ROM_FILE)?;
 
     let mut pc = 0;
 
@@ -117,14 +593,17 @@ fn disassemble_bootrom() -> Result<(), Box<Error>> {
             // DATA
             print!("{:02x} ", mmu.read_u8(pc)?);
             if pc == 0xdf {
-                println!("");
+                println!();
             }
             pc += 1;
             continue;
         }
 
         let (inst, delta) = Instruction::parse(pc, &mmu)?;
-        println!("{:04x}    {}", pc, inst);
+        if let Some(name) = symbols.name(BankedAddr::new(pc, 0)) {
+            println!("{}:", name);
+        }
+        println!("{:04x}    {}", pc, inst.with_symbols(pc, &symbols, 0));
         pc += delta;
     }
 
@@ -1,56 +1,218 @@
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::prelude::*;
 
-use crate::{error::Error, ppu::Ppu};
+use crate::{
+    apu::Apu,
+    error::Error,
+    interrupt::Interrupt,
+    joypad::Joypad,
+    ppu::Ppu,
+    serial::Serial,
+    state::{Snapshot, StateReader, StateWriter},
+    timer::Timer,
+};
+
+/// Where the boot rom is read from, relative to the working directory
+pub const BOOT_ROM_FILE: &str = "roms/DMG_ROM.bin";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A read or write through the memory bus, see `Mmu::set_watching`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub addr: u16,
+    pub kind: AccessKind,
+    pub value: u8,
+}
 
 pub struct Mmu {
     mem: Vec<u8>,
+    // The whole game rom, the first 32k of it is also in `mem`
+    rom: Vec<u8>,
+    boot_rom: Vec<u8>,
+    boot_rom_enabled: bool,
     pub ppu: Ppu,
+    pub joypad: Joypad,
+    pub timer: Timer,
+    pub serial: Serial,
+    pub apu: Apu,
+    // IF, 0xff0f
+    pub interrupt_flag: u8,
+    // IE, 0xffff
+    pub interrupt_enable: u8,
+    // Accesses are only recorded when watching, so normal runs just check the flag
+    watching: bool,
+    accesses: RefCell<Vec<Access>>,
 }
 
 impl Mmu {
     pub fn empty(ppu: Ppu) -> Mmu {
-        let mut mem = vec![0; 65535];
-        // Simulate always beeing in vblank :)
-        mem[0xff44] = 0x90;
-        Mmu { mem, ppu }
+        Mmu {
+            mem: vec![0; 0x10000],
+            rom: Vec::new(),
+            boot_rom: Vec::new(),
+            boot_rom_enabled: false,
+            ppu,
+            joypad: Joypad::default(),
+            timer: Timer::default(),
+            serial: Serial::default(),
+            apu: Apu::default(),
+            interrupt_flag: 0,
+            interrupt_enable: 0,
+            watching: false,
+            accesses: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Advance everything that is not the cpu by the given number of cycles
+    pub fn step(&mut self, cycles: u32) -> Result<(), Error> {
+        self.ppu.step(cycles)?;
+        self.timer.step(cycles);
+        self.serial.step(cycles);
+        self.apu.step(cycles);
+
+        self.interrupt_flag |= self.ppu.take_interrupts();
+        self.interrupt_flag |= self.joypad.take_interrupts();
+        self.interrupt_flag |= self.timer.take_interrupts();
+        self.interrupt_flag |= self.serial.take_interrupts();
+
+        Ok(())
+    }
+
+    /// Interrupts that are both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_enable & self.interrupt_flag & 0x1f
+    }
+
+    pub fn acknowledge_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag &= !interrupt.mask();
     }
 
     pub fn load_game_rom(&mut self, rom_file: &str) -> Result<(), Error> {
         let mut game_rom_file = File::open(rom_file)?;
         let mut game_rom = Vec::new();
         game_rom_file.read_to_end(&mut game_rom)?;
-        assert_eq!(game_rom.len(), 0x8000);
-        // TODO: We need to save the first 256 bytes and put that in when the booting is finished
-        // The first 256 bytes will be overwritten when the boot rom is loaded
-        let (left, _) = self.mem.split_at_mut(0x8000);
-        left.copy_from_slice(&game_rom);
+        if game_rom.len() < 0x8000 {
+            return Err(Error::InvalidRom(format!(
+                "{} is {} bytes, a rom is at least 32 KiB",
+                rom_file,
+                game_rom.len()
+            )));
+        }
+        self.load_rom(game_rom);
 
         Ok(())
     }
 
+    // Without a memory bank controller only the first two banks are mapped in
+    fn load_rom(&mut self, rom: Vec<u8>) {
+        let mapped = rom.len().min(0x8000);
+        self.mem[..mapped].copy_from_slice(&rom[..mapped]);
+        self.rom = rom;
+    }
+
+    /// A byte of the rom as it is at `addr` with `bank` mapped in at 4000 - 7fff, whatever
+    /// bank is mapped in now
+    pub fn read_rom_banked(&self, bank: u16, addr: u16) -> Result<u8, Error> {
+        let offset = match addr {
+            0x0000..=0x3fff => addr as usize,
+            0x4000..=0x7fff => bank as usize * 0x4000 + (addr - 0x4000) as usize,
+            _ => return Err(Error::InvalidReadFromMemoryLocation(addr)),
+        };
+        self.rom
+            .get(offset)
+            .cloned()
+            .ok_or(Error::NoSuchRomBank(bank))
+    }
+
+    /// Memory as the cpu would see it with another rom bank mapped in
+    pub fn with_rom_bank(&self, bank: u16) -> BankedMem<'_> {
+        BankedMem { mmu: self, bank }
+    }
+
+    /// Memory read without recording the accesses, for reading instructions rather than data
+    pub fn unrecorded(&self) -> Unrecorded<'_> {
+        Unrecorded { mmu: self }
+    }
+
+    /// The global checksum from the rom header, to tell games apart
+    pub fn rom_checksum(&self) -> u16 {
+        ((self.mem[0x14e] as u16) << 8) | self.mem[0x14f] as u16
+    }
+
+    /// The rom bank mapped at 4000 - 7fff. Without a memory bank controller it is always 1.
+    pub fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    /// Sum of the bytes in the boot rom, 0 if there is none
+    pub fn boot_rom_checksum(&self) -> u16 {
+        self.boot_rom
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+    }
+
     pub fn load_boot_rom(&mut self) -> Result<(), Error> {
-        let mut boot_rom_file = File::open("roms/DMG_ROM.bin")?;
+        let mut boot_rom_file = File::open(BOOT_ROM_FILE)?;
         let mut boot_rom = Vec::new();
         boot_rom_file.read_to_end(&mut boot_rom)?;
         assert_eq!(boot_rom.len(), 256);
 
-        let (left, _) = self.mem.split_at_mut(256);
-        left.copy_from_slice(&boot_rom);
+        // The boot rom is mapped over the first 256 bytes of the game rom until it writes to 0xff50
+        self.boot_rom = boot_rom;
+        self.boot_rom_enabled = true;
 
         Ok(())
     }
 
     pub fn dump_to_file(&self, filename: &str) -> Result<(), Error> {
-        // TODO: Use read_u8 to read IO registers correctly
+        let mem = (0..=0xffff)
+            .map(|addr| self.read_u8(addr))
+            .collect::<Result<Vec<u8>, Error>>()?;
         let mut file = File::create(filename)?;
-        file.write_all(&self.mem)?;
+        file.write_all(&mem)?;
         Ok(())
     }
 
+    /// Records every read and write, for the debugger's watchpoints
+    pub fn set_watching(&mut self, watching: bool) {
+        self.watching = watching;
+        self.accesses.get_mut().clear();
+    }
+
+    /// The accesses since the last call, oldest first
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        std::mem::take(self.accesses.get_mut())
+    }
+
+    fn record_access(&self, addr: u16, kind: AccessKind, value: u8) {
+        if self.watching {
+            self.accesses
+                .borrow_mut()
+                .push(Access { addr, kind, value });
+        }
+    }
+
     #[cfg(test)]
     pub fn with_mem(mem: Vec<u8>) -> Mmu {
-        Mmu { mem }
+        let (frames, _) = crate::frame::channel();
+        let mut mmu = Mmu::empty(Ppu::new(frames));
+        mmu.mem[..mem.len()].copy_from_slice(&mem);
+        mmu.rom = mem[..mem.len().min(0x8000)].to_vec();
+        mmu
+    }
+
+    #[cfg(test)]
+    pub fn with_rom(rom: Vec<u8>) -> Mmu {
+        let (frames, _) = crate::frame::channel();
+        let mut mmu = Mmu::empty(Ppu::new(frames));
+        mmu.load_rom(rom);
+        mmu
     }
 
     fn read_ram(&self, addr: u16) -> Result<u8, Error> {
@@ -71,32 +233,87 @@ impl Mmu {
         Ok(())
     }
 
+    fn read_io_register(&self, addr: u16) -> Result<u8, Error> {
+        match addr {
+            0xff00 => Ok(self.joypad.read()),
+            0xff01..=0xff02 => Ok(self.serial.read_register(addr)),
+            0xff04..=0xff07 => Ok(self.timer.read_register(addr)),
+            0xff10..=0xff3f => Ok(self.apu.read_register(addr)),
+            0xff0f => Ok(0xe0 | self.interrupt_flag),
+            0xff40..=0xff45 | 0xff47..=0xff4b => Ok(self.ppu.read_register(addr)),
+            0xffff => Ok(self.interrupt_enable),
+            _ => self.read_ram(addr),
+        }
+    }
+
     fn write_io_register(&mut self, addr: u16, val: u8) -> Result<(), Error> {
-        if addr == 0xff50 {
-            // TODO: Turn off bootrom
-            return Err(Error::Abort("Bootrom finished, aborting for now"));
+        match addr {
+            0xff00 => self.joypad.write(val),
+            0xff01..=0xff02 => self.serial.write_register(addr, val),
+            0xff04..=0xff07 => self.timer.write_register(addr, val),
+            0xff10..=0xff3f => self.apu.write_register(addr, val),
+            0xff0f => self.interrupt_flag = val & 0x1f,
+            0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.write_register(addr, val),
+            0xffff => self.interrupt_enable = val,
+            0xff46 => self.oam_dma(val)?,
+            0xff50 if val != 0 => self.boot_rom_enabled = false,
+            _ => (),
         }
         // Also write to ram for easier debugging and to make read and write work for now
         self.write_ram(addr, val)?;
         Ok(())
     }
 
+    // Copies 0xa0 bytes from 0xXX00 to OAM. On real hardware this takes 160 cycles,
+    // here it happens at once.
+    fn oam_dma(&mut self, val: u8) -> Result<(), Error> {
+        let src = (val as u16) << 8;
+        for i in 0..0xa0 {
+            self.ppu.oam[i as usize] = self.read_u8(src + i)?;
+        }
+        Ok(())
+    }
+
     pub fn read_u8(&self, addr: u16) -> Result<u8, Error> {
-        self.read_ram(addr)
+        let val = self.read_unrecorded(addr)?;
+        self.record_access(addr, AccessKind::Read, val);
+        Ok(val)
+    }
+
+    fn read_unrecorded(&self, addr: u16) -> Result<u8, Error> {
+        let val = match addr {
+            0x0000..=0x00ff if self.boot_rom_enabled => self.boot_rom[addr as usize],
+            0x8000..=0x9fff => self.ppu.vram[(addr - 0x8000) as usize],
+            0xfe00..=0xfe9f => self.ppu.oam[(addr - 0xfe00) as usize],
+            0xff00..=0xffff => self.read_io_register(addr)?,
+            _ => self.read_ram(addr)?,
+        };
+        Ok(val)
     }
 
     pub fn write_u8(&mut self, addr: u16, val: u8) -> Result<(), Error> {
+        self.record_access(addr, AccessKind::Write, val);
         match addr {
+            // No memory bank controller yet, so writes to the rom are ignored
+            0x0000..=0x7fff => Ok(()),
+            0x8000..=0x9fff => {
+                self.ppu.vram[(addr - 0x8000) as usize] = val;
+                Ok(())
+            }
+            0xfe00..=0xfe9f => {
+                self.ppu.oam[(addr - 0xfe00) as usize] = val;
+                Ok(())
+            }
             // IO
-            0x0000...0xfeff => self.write_ram(addr, val),
-            0xff00...0xffff => self.write_io_register(addr, val),
+            0xff00..=0xffff => self.write_io_register(addr, val),
+            _ => self.write_ram(addr, val),
         }
     }
 
     pub fn write_u16(&mut self, addr: u16, val: u16) -> Result<(), Error> {
         let high = (val >> 8) as u8;
         let low = (val & 0xff) as u8;
-        self.write_u8(addr + 1, high)?;
+        self.write_u8(addr.wrapping_add(1), high)?;
         self.write_u8(addr, low)?;
 
         Ok(())
@@ -108,8 +325,89 @@ impl Mmu {
 
     pub fn read_u16(&self, addr: u16) -> Result<u16, Error> {
         let first = self.read_u8(addr)?;
-        let second = self.read_u8(addr + 1)?;
+        let second = self.read_u8(addr.wrapping_add(1))?;
 
         Ok((first as u16) + ((second as u16) << 8))
     }
 }
+
+/// Something instructions can be read from
+pub trait ReadMem {
+    fn read_u8(&self, addr: u16) -> Result<u8, Error>;
+
+    fn read_i8(&self, addr: u16) -> Result<i8, Error> {
+        Ok(self.read_u8(addr)? as i8)
+    }
+
+    fn read_u16(&self, addr: u16) -> Result<u16, Error> {
+        let first = self.read_u8(addr)?;
+        let second = self.read_u8(addr.wrapping_add(1))?;
+
+        Ok((first as u16) + ((second as u16) << 8))
+    }
+}
+
+impl ReadMem for Mmu {
+    fn read_u8(&self, addr: u16) -> Result<u8, Error> {
+        Mmu::read_u8(self, addr)
+    }
+}
+
+/// See `Mmu::with_rom_bank`
+pub struct BankedMem<'a> {
+    mmu: &'a Mmu,
+    bank: u16,
+}
+
+impl ReadMem for BankedMem<'_> {
+    fn read_u8(&self, addr: u16) -> Result<u8, Error> {
+        match addr {
+            0x4000..=0x7fff => self.mmu.read_rom_banked(self.bank, addr),
+            _ => self.mmu.read_u8(addr),
+        }
+    }
+}
+
+/// See `Mmu::unrecorded`
+pub struct Unrecorded<'a> {
+    mmu: &'a Mmu,
+}
+
+impl ReadMem for Unrecorded<'_> {
+    fn read_u8(&self, addr: u16) -> Result<u8, Error> {
+        self.mmu.read_unrecorded(addr)
+    }
+}
+
+// The rom is not saved, only what the game can change
+impl Snapshot for Mmu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.mem[0x8000..]);
+        state.bool(self.boot_rom_enabled);
+        state.u8(self.interrupt_flag);
+        state.u8(self.interrupt_enable);
+        self.ppu.save_state(state);
+        self.joypad.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.apu.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        state.bytes(&mut self.mem[0x8000..])?;
+        self.boot_rom_enabled = state.bool()?;
+        if self.boot_rom_enabled && self.boot_rom.is_empty() {
+            return Err(Error::InvalidSaveState(
+                "the boot rom is mapped in, but none is loaded".to_string(),
+            ));
+        }
+        self.interrupt_flag = state.u8()?;
+        self.interrupt_enable = state.u8()?;
+        self.ppu.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.apu.load_state(state)?;
+        Ok(())
+    }
+}
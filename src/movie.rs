@@ -0,0 +1,249 @@
+// Input movies: the joypad state at the start of every frame, together with everything
+// needed to start the machine the same way. As the emulator does not depend on anything
+// else (the clock, random numbers, threads), playing it back gives the exact same run.
+//
+// File layout, little endian:
+//  "GBEMUMOV", version: u32, rom checksum: u16, model: u8, boot rom checksum: u16,
+//  start state length: u32, start state, frame count: u32, one byte per frame
+
+use std::fs;
+
+use crate::{error::Error, gameboy::Gameboy};
+
+const MAGIC: &[u8; 8] = b"GBEMUMOV";
+const VERSION: u32 = 1;
+
+// Only the original Game Boy for now
+const MODEL_DMG: u8 = 0;
+
+pub struct Movie {
+    rom_checksum: u16,
+    model: u8,
+    boot_rom_checksum: u16,
+    start_state: Vec<u8>,
+    inputs: Vec<u8>,
+}
+
+impl Movie {
+    /// An empty movie starting at the current state of the machine
+    pub fn new(gameboy: &Gameboy) -> Movie {
+        Movie {
+            rom_checksum: gameboy.mmu.rom_checksum(),
+            model: MODEL_DMG,
+            boot_rom_checksum: gameboy.mmu.boot_rom_checksum(),
+            start_state: gameboy.save_state(),
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn load(filename: &str) -> Result<Movie, Error> {
+        let data = fs::read(filename)?;
+        let invalid = || Error::InvalidMovie(format!("{} is cut short", filename));
+        if !data.starts_with(MAGIC) {
+            return Err(Error::InvalidMovie(format!("{} is not a movie", filename)));
+        }
+
+        let mut pos = MAGIC.len();
+        let mut take = |len: usize| -> Result<&[u8], Error> {
+            let bytes = data.get(pos..pos + len).ok_or_else(invalid)?;
+            pos += len;
+            Ok(bytes)
+        };
+        let u16_at = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]);
+        let u32_at = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+        let version = u32_at(take(4)?);
+        if version != VERSION {
+            return Err(Error::InvalidMovie(format!(
+                "version {} is not supported",
+                version
+            )));
+        }
+        let rom_checksum = u16_at(take(2)?);
+        let model = take(1)?[0];
+        let boot_rom_checksum = u16_at(take(2)?);
+        let len = u32_at(take(4)?) as usize;
+        let start_state = take(len)?.to_vec();
+        let len = u32_at(take(4)?) as usize;
+        let inputs = take(len)?.to_vec();
+
+        Ok(Movie {
+            rom_checksum,
+            model,
+            boot_rom_checksum,
+            start_state,
+            inputs,
+        })
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Error> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&self.rom_checksum.to_le_bytes());
+        data.push(self.model);
+        data.extend_from_slice(&self.boot_rom_checksum.to_le_bytes());
+        data.extend_from_slice(&(self.start_state.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.start_state);
+        data.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.inputs);
+        fs::write(filename, data)?;
+        Ok(())
+    }
+
+    /// Puts the machine in the state the movie starts from
+    fn start(&self, gameboy: &mut Gameboy) -> Result<(), Error> {
+        if self.model != MODEL_DMG {
+            return Err(Error::InvalidMovie(format!("unknown model {}", self.model)));
+        }
+        if self.rom_checksum != gameboy.mmu.rom_checksum() {
+            return Err(Error::InvalidMovie("recorded with another rom".to_string()));
+        }
+        if self.boot_rom_checksum != gameboy.mmu.boot_rom_checksum() {
+            return Err(Error::InvalidMovie(
+                "recorded with another boot rom".to_string(),
+            ));
+        }
+        gameboy.load_state(&self.start_state)
+    }
+}
+
+/// Recording or playing back a movie, driven by the game loop
+pub enum MovieSession {
+    Off,
+    Record { movie: Movie, filename: String },
+    Replay { movie: Movie, frame: usize },
+}
+
+impl MovieSession {
+    pub fn record(gameboy: &Gameboy, filename: &str) -> MovieSession {
+        MovieSession::Record {
+            movie: Movie::new(gameboy),
+            filename: filename.to_string(),
+        }
+    }
+
+    pub fn replay(gameboy: &mut Gameboy, movie: Movie) -> Result<MovieSession, Error> {
+        movie.start(gameboy)?;
+        Ok(MovieSession::Replay { movie, frame: 0 })
+    }
+
+    /// Call before running each frame. Records the joypad, or sets it from the movie.
+    /// When the movie has been played to the end the session turns off.
+    pub fn before_frame(&mut self, gameboy: &mut Gameboy) {
+        match self {
+            MovieSession::Off => (),
+            MovieSession::Record { movie, .. } => movie.inputs.push(gameboy.mmu.joypad.pressed()),
+            MovieSession::Replay { movie, frame } => match movie.inputs.get(*frame) {
+                Some(&pressed) => {
+                    gameboy.mmu.joypad.set_pressed(pressed);
+                    *frame += 1;
+                }
+                None => {
+                    println!("Movie finished after {} frames", frame);
+                    *self = MovieSession::Off;
+                }
+            },
+        }
+    }
+
+    /// Input from the keyboard is ignored while playing back
+    pub fn is_replaying(&self) -> bool {
+        matches!(self, MovieSession::Replay { .. })
+    }
+
+    /// Loading states and rewinding would make the recording impossible to play back
+    pub fn allows_state_changes(&self) -> bool {
+        matches!(self, MovieSession::Off)
+    }
+
+    /// Writes the recording to its file
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if let MovieSession::Record { movie, filename } = self {
+            movie.save(filename)?;
+            println!("Recorded {} frames to {}", movie.frames(), filename);
+        }
+        *self = MovieSession::Off;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cpu::Cpu, frame, mem::Mmu, ppu::Ppu};
+
+    #[test]
+    fn test_save_and_load() {
+        let (frames, _consumer) = frame::channel();
+        let gameboy = Gameboy::new(Mmu::empty(Ppu::new(frames)), Cpu::default());
+        let mut movie = Movie::new(&gameboy);
+        movie.inputs = vec![0x00, 0x01, 0x80];
+
+        let filename = std::env::temp_dir().join("gbemu-movie-test.gbm");
+        let filename = filename.to_str().unwrap();
+        movie.save(filename).unwrap();
+        let loaded = Movie::load(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+
+        assert_eq!(loaded.inputs, movie.inputs);
+        assert_eq!(loaded.start_state, movie.start_state);
+        assert_eq!(loaded.rom_checksum, movie.rom_checksum);
+    }
+
+    // Writes what it reads from the joypad to c000 - c0ff, over and over
+    fn joypad_logger() -> Gameboy {
+        let rom = vec![
+            0x21, 0x00, 0xc0, // 0000: ld hl,c000
+            0x3e, 0x20, // 0003: ld a,20
+            0xe0, 0x00, // 0005: ldh (00),a
+            0xf0, 0x00, // 0007: ldh a,(00)
+            0x77, // 0009: ld (hl),a
+            0x2c, // 000a: inc l
+            0x18, 0xf6, // 000b: jr 0003
+        ];
+        Gameboy::new(Mmu::with_mem(rom), Cpu::default())
+    }
+
+    fn run(gameboy: &mut Gameboy, session: &mut MovieSession, frames: u32) {
+        for _ in 0..frames {
+            session.before_frame(gameboy);
+            gameboy.run_frame().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replay_reproduces_the_run() {
+        let mut gameboy = joypad_logger();
+        let mut session = MovieSession::record(&gameboy, "unused.gbm");
+        for frame in 0..10 {
+            gameboy.mmu.joypad.set_pressed(frame * 0x19);
+            run(&mut gameboy, &mut session, 1);
+        }
+        let movie = match session {
+            MovieSession::Record { movie, .. } => movie,
+            _ => unreachable!(),
+        };
+
+        // Whatever the machine did before, the replay starts from the recorded state
+        let mut replayed = joypad_logger();
+        run(&mut replayed, &mut MovieSession::Off, 3);
+        let mut session = MovieSession::replay(&mut replayed, movie).unwrap();
+        run(&mut replayed, &mut session, 10);
+
+        assert!(session.is_replaying());
+        assert_eq!(replayed.cpu.pc, gameboy.cpu.pc);
+        assert_eq!(replayed.cpu.a, gameboy.cpu.a);
+        assert_eq!(replayed.cpu.get_hl(), gameboy.cpu.get_hl());
+        assert_eq!(replayed.cycles(), gameboy.cycles());
+        assert_eq!(replayed.save_state(), gameboy.save_state());
+
+        // The inputs made a difference
+        let mut unrecorded = joypad_logger();
+        run(&mut unrecorded, &mut MovieSession::Off, 10);
+        assert_ne!(unrecorded.save_state(), gameboy.save_state());
+    }
+}
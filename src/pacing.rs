@@ -0,0 +1,136 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::gameboy::CYCLES_PER_FRAME;
+
+/// The DMG runs at 4194304 Hz and draws a frame every 70224 cycles, about 59.7 frames per second
+pub const FRAME_RATE: f64 = 4_194_304.0 / CYCLES_PER_FRAME as f64;
+
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 8.0;
+
+/// Keeps the emulator running at the speed of a real Game Boy (times a multiplier)
+pub struct FramePacer {
+    speed: f64,
+    uncapped: bool,
+    next_frame: Instant,
+}
+
+impl FramePacer {
+    pub fn new(speed: f64, uncapped: bool) -> FramePacer {
+        FramePacer {
+            speed: speed.clamp(MIN_SPEED, MAX_SPEED),
+            uncapped,
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// Sleeps until it is time to start the next frame
+    pub fn wait(&mut self) {
+        if self.uncapped {
+            return;
+        }
+
+        let frame_time = self.frame_time();
+        self.next_frame += frame_time;
+
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > frame_time * 4 {
+            // We are too far behind to catch up, so don't try
+            self.next_frame = now;
+        }
+    }
+
+    fn frame_time(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / (FRAME_RATE * self.speed))
+    }
+
+    pub fn speed_up(&mut self) {
+        self.set_speed(self.speed * 2.0);
+    }
+
+    pub fn slow_down(&mut self) {
+        self.set_speed(self.speed / 2.0);
+    }
+
+    pub fn reset_speed(&mut self) {
+        self.uncapped = false;
+        self.set_speed(1.0);
+    }
+
+    pub fn toggle_uncapped(&mut self) {
+        self.uncapped = !self.uncapped;
+        self.next_frame = Instant::now();
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.next_frame = Instant::now();
+    }
+}
+
+/// A speed multiplier from the command line, NaN and infinity would break the frame timing
+pub fn parse_speed(src: &str) -> Result<f64, String> {
+    let speed: f64 = src.parse().map_err(|err| format!("{}", err))?;
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err(format!("the speed must be a positive number, not {}", src));
+    }
+    Ok(speed)
+}
+
+impl std::fmt::Display for FramePacer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.uncapped {
+            write!(f, "uncapped")
+        } else {
+            write!(f, "{}x", self.speed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_speed_is_clamped() {
+        assert_eq!(FramePacer::new(0.01, false).speed, MIN_SPEED);
+        assert_eq!(FramePacer::new(100.0, false).speed, MAX_SPEED);
+
+        let mut pacer = FramePacer::new(1.0, false);
+        pacer.speed_up();
+        assert_eq!(pacer.to_string(), "2x");
+        for _ in 0..10 {
+            pacer.speed_up();
+        }
+        assert_eq!(pacer.speed, MAX_SPEED);
+        for _ in 0..10 {
+            pacer.slow_down();
+        }
+        assert_eq!(pacer.speed, MIN_SPEED);
+
+        pacer.toggle_uncapped();
+        assert_eq!(pacer.to_string(), "uncapped");
+        pacer.reset_speed();
+        assert_eq!(pacer.to_string(), "1x");
+    }
+
+    #[test]
+    fn test_frame_time() {
+        let mut pacer = FramePacer::new(1.0, false);
+        assert_eq!(pacer.frame_time().as_micros(), 16742);
+        pacer.speed_up();
+        assert_eq!(pacer.frame_time().as_micros(), 8371);
+    }
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(parse_speed("0.5"), Ok(0.5));
+        assert!(parse_speed("nan").is_err());
+        assert!(parse_speed("inf").is_err());
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+}
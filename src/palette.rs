@@ -0,0 +1,148 @@
+use crate::error::Error;
+
+/// Maps the four DMG shades (0 is lightest, 3 is darkest) to RGB colors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub name: String,
+    pub colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+    pub fn new(name: &str, colors: [[u8; 3]; 4]) -> Palette {
+        Palette {
+            name: name.to_string(),
+            colors,
+        }
+    }
+
+    pub fn classic_green() -> Palette {
+        Palette::new(
+            "classic-green",
+            [
+                [0x9b, 0xbc, 0x0f],
+                [0x8b, 0xac, 0x0f],
+                [0x30, 0x62, 0x30],
+                [0x0f, 0x38, 0x0f],
+            ],
+        )
+    }
+
+    pub fn pocket_gray() -> Palette {
+        Palette::new(
+            "pocket-gray",
+            [
+                [0xe0, 0xdb, 0xcd],
+                [0xa8, 0x9f, 0x94],
+                [0x70, 0x6b, 0x66],
+                [0x2b, 0x2b, 0x26],
+            ],
+        )
+    }
+
+    pub fn high_contrast() -> Palette {
+        Palette::new(
+            "high-contrast",
+            [
+                [0xff, 0xff, 0xff],
+                [0xaa, 0xaa, 0xaa],
+                [0x55, 0x55, 0x55],
+                [0x00, 0x00, 0x00],
+            ],
+        )
+    }
+
+    pub fn builtin() -> Vec<Palette> {
+        vec![
+            Palette::pocket_gray(),
+            Palette::classic_green(),
+            Palette::high_contrast(),
+        ]
+    }
+
+    /// Creates a palette from four colors written as `#rrggbb`
+    pub fn from_hex(name: &str, colors: &[String]) -> Result<Palette, Error> {
+        if colors.len() != 4 {
+            return Err(Error::InvalidPalette(format!(
+                "palette `{}` needs 4 colors, got {}",
+                name,
+                colors.len()
+            )));
+        }
+
+        let mut rgb = [[0; 3]; 4];
+        for (dst, color) in rgb.iter_mut().zip(colors) {
+            *dst = parse_hex_color(color)?;
+        }
+
+        Ok(Palette::new(name, rgb))
+    }
+
+    pub fn rgb(&self, shade: u8) -> [u8; 3] {
+        self.colors[shade as usize]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::pocket_gray()
+    }
+}
+
+fn parse_hex_color(color: &str) -> Result<[u8; 3], Error> {
+    let invalid = || Error::InvalidPalette(format!("invalid color `{}`", color));
+
+    let hex = color.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(invalid());
+    }
+    let val = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+
+    Ok([(val >> 16) as u8, (val >> 8) as u8, val as u8])
+}
+
+/// The palettes that can be cycled through while running
+pub struct PaletteList {
+    palettes: Vec<Palette>,
+    current: usize,
+}
+
+impl PaletteList {
+    pub fn new(palettes: Vec<Palette>, selected: Option<&str>) -> Result<PaletteList, Error> {
+        let current = match selected {
+            Some(name) => palettes
+                .iter()
+                .position(|palette| palette.name == name)
+                .ok_or_else(|| Error::InvalidPalette(format!("unknown palette `{}`", name)))?,
+            None => 0,
+        };
+
+        Ok(PaletteList { palettes, current })
+    }
+
+    pub fn current(&self) -> &Palette {
+        &self.palettes[self.current]
+    }
+
+    pub fn cycle(&mut self) -> &Palette {
+        self.current = (self.current + 1) % self.palettes.len();
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_hex() {
+        let colors: Vec<String> = vec!["#ffffff", "#a0b1c2", "555555", "#000000"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let palette = Palette::from_hex("custom", &colors).unwrap();
+        assert_eq!(palette.rgb(1), [0xa0, 0xb1, 0xc2]);
+        assert_eq!(palette.rgb(2), [0x55, 0x55, 0x55]);
+
+        assert!(Palette::from_hex("custom", &colors[..3]).is_err());
+    }
+}
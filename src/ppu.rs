@@ -1,8 +1,10 @@
-use std::sync::Arc;
-
-use parking_lot::Mutex;
-
-use crate::{error::Error, mem::Mmu};
+use crate::{
+    error::Error,
+    frame::FrameProducer,
+    interrupt::Interrupt,
+    palette::Palette,
+    state::{Snapshot, StateReader, StateWriter},
+};
 
 // screen is 20 tiles by 18 tiles (160x144pixels)
 // Viewport on a 32x32 tiles map (wrapping around)
@@ -33,32 +35,433 @@ use crate::{error::Error, mem::Mmu};
 // Gameboyen er klokket til 1 048 576 klokker pr sekund
 // Så mao 1 048 576 / 17556 = 59.7 Hz
 
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// Measured in clocks of the 4 MHz main clock (4 per "klokke" above)
+const OAM_SEARCH_CYCLES: u32 = 80;
+const PIXEL_TRANSFER_CYCLES: u32 = 172;
+const LINE_CYCLES: u32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamSearch = 2,
+    PixelTransfer = 3,
+}
+
 pub struct Ppu {
-    display: Arc<Mutex<Vec<u8>>>,
+    // Lines are drawn straight into the back buffer, and it is presented at vblank
+    frames: FrameProducer,
     pub vram: Vec<u8>,
-    buffer: Vec<u8>,
+    pub oam: Vec<u8>,
+    // How the four shades of gray ends up on the screen
+    palette: Palette,
     current_line: u8,
-    next_pixel: u8,
+    line_cycles: u32,
+    mode: Mode,
+    // The window has its own line counter, which only increases on lines where it is visible
+    window_line: u8,
+    frame_count: u64,
+    interrupts: u8,
+    // The STAT interrupt is requested when any of the enabled conditions becomes true
+    stat_line: bool,
+    // 0xff40
+    lcdc: u8,
+    // 0xff41
+    stat: u8,
+    // 0xff42
+    scy: u8,
+    // 0xff43
+    scx: u8,
+    // 0xff45
+    lyc: u8,
+    // 0xff47
+    bgp: u8,
+    // 0xff48
+    obp0: u8,
+    // 0xff49
+    obp1: u8,
+    // 0xff4a
+    wy: u8,
+    // 0xff4b
+    wx: u8,
 }
 
 impl Ppu {
-    pub fn new(display: Arc<Mutex<Vec<u8>>>) -> Self {
+    pub fn new(frames: FrameProducer) -> Self {
         Ppu {
-            display,
+            frames,
             vram: vec![0; 0x2000],
-            buffer: vec![0; 160 * 144 * 3],
+            oam: vec![0; 0xa0],
+            palette: Palette::default(),
             current_line: 0,
-            next_pixel: 0,
+            line_cycles: 0,
+            mode: Mode::OamSearch,
+            window_line: 0,
+            frame_count: 0,
+            interrupts: 0,
+            stat_line: false,
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+        }
+    }
+
+    /// Number of frames that has been sent to the display
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Interrupts requested since the last call
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::replace(&mut self.interrupts, 0)
+    }
+
+    /// True when the display has gone away, and there is no point in running anymore
+    pub fn display_closed(&self) -> bool {
+        self.frames.is_closed()
+    }
+
+    /// Changes the colors used from the next line that is drawn
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff40 => self.lcdc,
+            0xff41 => {
+                let coincidence = if self.current_line == self.lyc {
+                    0x04
+                } else {
+                    0
+                };
+                0x80 | (self.stat & 0x78) | coincidence | self.mode as u8
+            }
+            0xff42 => self.scy,
+            0xff43 => self.scx,
+            0xff44 => self.current_line,
+            0xff45 => self.lyc,
+            0xff47 => self.bgp,
+            0xff48 => self.obp0,
+            0xff49 => self.obp1,
+            0xff4a => self.wy,
+            0xff4b => self.wx,
+            _ => 0xff,
         }
     }
 
-    pub fn step(&mut self) -> Result<(), Error> {
-        // copy 4 pixels each time. TODO: Implement Pixel FIFO
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff40 => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = val;
+                if was_enabled && !self.lcd_enabled() {
+                    // Turning off the LCD resets LY and the mode
+                    self.current_line = 0;
+                    self.line_cycles = 0;
+                    self.mode = Mode::HBlank;
+                } else if !was_enabled && self.lcd_enabled() {
+                    self.mode = Mode::OamSearch;
+                }
+            }
+            0xff41 => self.stat = val & 0x78,
+            0xff42 => self.scy = val,
+            0xff43 => self.scx = val,
+            // LY is read only
+            0xff44 => (),
+            0xff45 => self.lyc = val,
+            0xff47 => self.bgp = val,
+            0xff48 => self.obp0 = val,
+            0xff49 => self.obp1 = val,
+            0xff4a => self.wy = val,
+            0xff4b => self.wx = val,
+            _ => (),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) -> Result<(), Error> {
+        if !self.lcd_enabled() {
+            return Ok(());
+        }
+
+        self.line_cycles += cycles;
+
+        if self.mode == Mode::OamSearch && self.line_cycles >= OAM_SEARCH_CYCLES {
+            self.mode = Mode::PixelTransfer;
+        }
+        if self.mode == Mode::PixelTransfer
+            && self.line_cycles >= OAM_SEARCH_CYCLES + PIXEL_TRANSFER_CYCLES
+        {
+            // TODO: Implement Pixel FIFO. For now the whole line is drawn at once
+            self.render_line();
+            self.mode = Mode::HBlank;
+        }
+
+        if self.line_cycles >= LINE_CYCLES {
+            self.line_cycles -= LINE_CYCLES;
+            self.current_line += 1;
+
+            if self.current_line == SCREEN_HEIGHT as u8 {
+                self.mode = Mode::VBlank;
+                self.interrupts |= Interrupt::VBlank.mask();
+                self.frames.present();
+                self.frame_count += 1;
+            } else if self.current_line == LINES_PER_FRAME {
+                self.current_line = 0;
+                self.window_line = 0;
+            }
 
-        for i in 0..5 {
-            println!("{}", i);
+            if self.current_line < SCREEN_HEIGHT as u8 {
+                self.mode = Mode::OamSearch;
+            }
         }
 
+        self.update_stat_line();
+
         Ok(())
     }
+
+    fn update_stat_line(&mut self) {
+        let line = (self.stat & 0x40 != 0 && self.current_line == self.lyc)
+            || (self.stat & 0x20 != 0 && self.mode == Mode::OamSearch)
+            || (self.stat & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (self.stat & 0x08 != 0 && self.mode == Mode::HBlank);
+
+        if line && !self.stat_line {
+            self.interrupts |= Interrupt::LcdStat.mask();
+        }
+        self.stat_line = line;
+    }
+
+    // Returns the color number (0-3) of one pixel in a tile
+    fn tile_pixel(&self, tile_addr: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[tile_addr + (y as usize) * 2];
+        let high = self.vram[tile_addr + (y as usize) * 2 + 1];
+        let bit = 7 - x;
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    // Address (relative to start of vram) of a tile used by the background or window
+    fn bg_tile_addr(&self, tile: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            (tile as usize) * 16
+        } else {
+            (0x1000 + (tile as i8 as i32) * 16) as usize
+        }
+    }
+
+    fn render_line(&mut self) {
+        let ly = self.current_line;
+        // Color numbers before the palette is applied, needed for sprite priority
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+
+        if self.lcdc & 0x01 != 0 {
+            let window_visible = self.lcdc & 0x20 != 0 && ly >= self.wy && self.wx <= 166;
+
+            for (x, bg_color) in bg_colors.iter_mut().enumerate() {
+                let x = x as u8;
+                let (map, map_x, map_y) = if window_visible && x + 7 >= self.wx {
+                    let map = if self.lcdc & 0x40 != 0 {
+                        0x1c00
+                    } else {
+                        0x1800
+                    };
+                    (map, x + 7 - self.wx, self.window_line)
+                } else {
+                    let map = if self.lcdc & 0x08 != 0 {
+                        0x1c00
+                    } else {
+                        0x1800
+                    };
+                    (map, x.wrapping_add(self.scx), ly.wrapping_add(self.scy))
+                };
+
+                let tile_index = map + (map_y as usize / 8) * 32 + (map_x as usize / 8);
+                let tile_addr = self.bg_tile_addr(self.vram[tile_index]);
+                *bg_color = self.tile_pixel(tile_addr, map_x % 8, map_y % 8);
+            }
+
+            if window_visible {
+                self.window_line += 1;
+            }
+        }
+
+        for (x, &color) in bg_colors.iter().enumerate() {
+            self.set_pixel(x, ly as usize, apply_palette(self.bgp, color));
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites(ly, &bg_colors);
+        }
+    }
+
+    fn render_sprites(&mut self, ly: u8, bg_colors: &[u8; SCREEN_WIDTH]) {
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+
+        // At most 10 sprites per line, picked in OAM order
+        let mut sprites: Vec<usize> = (0..40)
+            .filter(|i| {
+                let y = self.oam[i * 4] as i16 - 16;
+                (ly as i16) >= y && (ly as i16) < y + height
+            })
+            .take(10)
+            .collect();
+
+        // Sprites with the lowest x has the highest priority, then the first in OAM.
+        // Draw the ones with lowest priority first so they are overwritten.
+        sprites.sort_by_key(|&i| (self.oam[i * 4 + 1], i));
+        for &i in sprites.iter().rev() {
+            let y = self.oam[i * 4] as i16 - 16;
+            let x = self.oam[i * 4 + 1] as i16 - 8;
+            let mut tile = self.oam[i * 4 + 2];
+            let attributes = self.oam[i * 4 + 3];
+
+            let behind_bg = attributes & 0x80 != 0;
+            let y_flip = attributes & 0x40 != 0;
+            let x_flip = attributes & 0x20 != 0;
+            let palette = if attributes & 0x10 != 0 {
+                self.obp1
+            } else {
+                self.obp0
+            };
+
+            let mut row = (ly as i16 - y) as u8;
+            if y_flip {
+                row = height as u8 - 1 - row;
+            }
+            if height == 16 {
+                tile &= 0xfe;
+            }
+            let tile_addr = (tile as usize) * 16 + (row as usize / 8) * 16;
+
+            for col in 0..8u8 {
+                let screen_x = x + col as i16;
+                if screen_x < 0 || screen_x >= SCREEN_WIDTH as i16 {
+                    continue;
+                }
+                let tile_col = if x_flip { 7 - col } else { col };
+                let color = self.tile_pixel(tile_addr, tile_col, row % 8);
+                // Color 0 is transparent for sprites
+                if color == 0 || (behind_bg && bg_colors[screen_x as usize] != 0) {
+                    continue;
+                }
+                self.set_pixel(
+                    screen_x as usize,
+                    ly as usize,
+                    apply_palette(palette, color),
+                );
+            }
+        }
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, shade: u8) {
+        let offset = (y * SCREEN_WIDTH + x) * 3;
+        let rgb = self.palette.rgb(shade);
+        self.frames.back_buffer()[offset..offset + 3].copy_from_slice(&rgb);
+    }
+}
+
+// Palettes map color numbers to shades, 2 bits per color
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+// The palette is a display setting, so it is not part of the state
+impl Snapshot for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.vram);
+        state.bytes(&self.oam);
+        state.u8(self.current_line);
+        state.u32(self.line_cycles);
+        state.u8(self.mode as u8);
+        state.u8(self.window_line);
+        state.u64(self.frame_count);
+        state.u8(self.interrupts);
+        state.bool(self.stat_line);
+        for &reg in &[
+            self.lcdc, self.stat, self.scy, self.scx, self.lyc, self.bgp, self.obp0, self.obp1,
+            self.wy, self.wx,
+        ] {
+            state.u8(reg);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        state.bytes(&mut self.vram)?;
+        state.bytes(&mut self.oam)?;
+        self.current_line = state.u8_at_most(LINES_PER_FRAME - 1, "ppu line")?;
+        self.line_cycles = state.u32_at_most(LINE_CYCLES - 1, "ppu line cycles")?;
+        self.mode = match state.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamSearch,
+            3 => Mode::PixelTransfer,
+            mode => {
+                return Err(Error::InvalidSaveState(format!(
+                    "unknown ppu mode {}",
+                    mode
+                )))
+            }
+        };
+        self.window_line = state.u8_at_most(SCREEN_HEIGHT as u8, "ppu window line")?;
+        self.frame_count = state.u64()?;
+        self.interrupts = state.u8()?;
+        self.stat_line = state.bool()?;
+        for reg in &mut [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            **reg = state.u8()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame;
+
+    #[test]
+    fn test_load_state_checks_ranges() {
+        let (frames, _consumer) = frame::channel();
+        let mut ppu = Ppu::new(frames);
+        let mut state = StateWriter::new(0);
+        ppu.save_state(&mut state);
+        let mut data = state.into_bytes();
+
+        // After the header, the vram and the oam
+        let line = 14 + 4 + 0x2000 + 4 + 0xa0;
+        data[line] = LINES_PER_FRAME;
+        assert!(ppu
+            .load_state(&mut StateReader::new(&data, 0).unwrap())
+            .is_err());
+        data[line] = LINES_PER_FRAME - 1;
+        ppu.load_state(&mut StateReader::new(&data, 0).unwrap())
+            .unwrap();
+        assert_eq!(ppu.read_register(0xff44), LINES_PER_FRAME - 1);
+    }
 }
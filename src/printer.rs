@@ -0,0 +1,345 @@
+use crate::{headless::save_png, link::Link};
+
+// Every packet starts with these two bytes
+const MAGIC: [u8; 2] = [0x88, 0x33];
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0f;
+
+// Status bits
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+// The printer is 160 pixels (20 tiles) wide and holds at most 9 packets of 2 tile rows
+const TILES_PER_ROW: usize = 20;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * 16;
+const BUFFER_SIZE: usize = 0x2000;
+
+// How many status packets report that we are still printing, games wait for it to finish
+const PRINT_STATUS_POLLS: u8 = 4;
+
+// White paper, black ink
+const SHADES: [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    // The printer answers 0x81 to say it is there
+    Alive,
+    Status,
+}
+
+/// The Game Boy Printer, connected to the serial port in place of a link cable.
+///
+/// The game sends packets of `88 33 <command> <compression> <length:2> <data> <checksum:2> 00 00`.
+/// The printer answers 0x81 to the first trailing zero and its status to the second.
+/// Image data is collected as tiles until a print command, then the strip is written
+/// to `<prefix>-<n>.png`.
+pub struct Printer {
+    prefix: String,
+    prints: u32,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    checksum: u16,
+    packet: Vec<u8>,
+    image: Vec<u8>,
+    status: u8,
+    printing_polls: u8,
+}
+
+impl Printer {
+    pub fn new(prefix: &str) -> Printer {
+        Printer {
+            prefix: prefix.to_string(),
+            prints: 0,
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            checksum: 0,
+            packet: Vec::new(),
+            image: Vec::new(),
+            status: 0,
+            printing_polls: 0,
+        }
+    }
+
+    // Takes one byte from the game and returns the byte the printer sends back
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic(i) if byte == MAGIC[i] => {
+                if i + 1 == MAGIC.len() {
+                    State::Command
+                } else {
+                    State::Magic(i + 1)
+                }
+            }
+            // Out of sync, wait for the start of the next packet
+            State::Magic(_) => State::Magic(0),
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.packet.clear();
+                if self.length == 0 {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::Data => {
+                self.packet.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.packet.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::ChecksumLow => {
+                self.checksum ^= byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.checksum ^= (byte as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                reply = 0x81;
+                State::Status
+            }
+            State::Status => {
+                self.handle_packet();
+                reply = self.status;
+                State::Magic(0)
+            }
+        };
+        reply
+    }
+
+    fn handle_packet(&mut self) {
+        // The checksum was xor'ed with the received one, so it is 0 if they matched
+        if self.checksum != 0 {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.image.clear();
+                self.status = 0;
+                self.printing_polls = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.packet)
+                } else {
+                    self.packet.clone()
+                };
+                let space = BUFFER_SIZE - self.image.len();
+                self.image.extend(data.iter().take(space));
+                if !self.image.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+            }
+            COMMAND_PRINT => {
+                let palette = self.packet.get(2).cloned().unwrap_or(0);
+                self.print(palette);
+                self.status &= !STATUS_UNPROCESSED_DATA;
+                self.status |= STATUS_PRINTING;
+                self.printing_polls = PRINT_STATUS_POLLS;
+            }
+            COMMAND_STATUS => {
+                if self.printing_polls > 0 {
+                    self.printing_polls -= 1;
+                } else {
+                    self.status &= !STATUS_PRINTING;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn print(&mut self, palette: u8) {
+        let rows = self.image.len() / BYTES_PER_TILE_ROW;
+        if rows == 0 {
+            return;
+        }
+
+        let rgb = render(&self.image[..rows * BYTES_PER_TILE_ROW], palette);
+        self.image.clear();
+        self.prints += 1;
+        let filename = format!("{}-{}.png", self.prefix, self.prints);
+        let height = (rows * 8) as u32;
+        match save_png(&filename, (TILES_PER_ROW * 8) as u32, height, &rgb) {
+            Ok(()) => println!("Printed to {}", filename),
+            Err(err) => println!("Printing to {} failed: {}", filename, err),
+        }
+    }
+}
+
+impl Link for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+
+    // The printer never drives the clock
+    fn poll(&mut self, _reply: u8) -> Option<u8> {
+        None
+    }
+}
+
+// A control byte with the top bit clear is followed by (n + 1) bytes to copy as is,
+// with the top bit set it is followed by one byte to repeat ((n & 0x7f) + 2) times.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            let count = (control & 0x7f) as usize + 2;
+            if let Some(&byte) = data.get(i) {
+                out.extend(std::iter::repeat(byte).take(count));
+            }
+            i += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = (i + count).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+// Turns rows of 20 tiles into RGB pixels. The palette maps each color number to a shade,
+// two bits per color with color 0 in the lowest bits, like BGP.
+fn render(tiles: &[u8], palette: u8) -> Vec<u8> {
+    // 0 is used by some games to mean the default palette
+    let palette = if palette == 0 { 0xe4 } else { palette };
+    let width = TILES_PER_ROW * 8;
+    let height = tiles.len() / BYTES_PER_TILE_ROW * 8;
+    let mut rgb = vec![0; width * height * 3];
+
+    for (tile_index, tile) in tiles.chunks(16).enumerate() {
+        let tile_x = (tile_index % TILES_PER_ROW) * 8;
+        let tile_y = (tile_index / TILES_PER_ROW) * 8;
+        for row in 0..8 {
+            let low = tile[row * 2];
+            let high = tile[row * 2 + 1];
+            for col in 0..8 {
+                let bit = 7 - col;
+                let color = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                let shade = SHADES[((palette >> (color * 2)) & 0x03) as usize];
+                let pixel = ((tile_y + row) * width + tile_x + col) * 3;
+                rgb[pixel..pixel + 3].copy_from_slice(&[shade; 3]);
+            }
+        }
+    }
+    rgb
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> u8 {
+        let mut packet = vec![command, compressed as u8];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(data);
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(packet);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        for byte in bytes {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+        assert_eq!(printer.exchange(0x00), 0x81);
+        printer.exchange(0x00)
+    }
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(
+            decompress(&[0x01, 0xaa, 0xbb, 0x81, 0xcc]),
+            vec![0xaa, 0xbb, 0xcc, 0xcc, 0xcc]
+        );
+    }
+
+    #[test]
+    fn test_print() {
+        let prefix = std::env::temp_dir().join("gbemu-printer-test");
+        let mut printer = Printer::new(prefix.to_str().unwrap());
+        assert_eq!(send_packet(&mut printer, COMMAND_INIT, false, &[]), 0);
+
+        // One row of black tiles, 320 bytes of 0xff
+        let data = [0xff, 0xff, 0xff, 0xff, 0xbc, 0xff];
+        let status = send_packet(&mut printer, COMMAND_DATA, true, &data);
+        assert_eq!(status, STATUS_UNPROCESSED_DATA);
+        assert_eq!(printer.image.len(), BYTES_PER_TILE_ROW);
+
+        let status = send_packet(&mut printer, COMMAND_PRINT, false, &[1, 0x13, 0xe4, 0x40]);
+        assert_eq!(status, STATUS_PRINTING);
+        let filename = format!("{}-1.png", prefix.to_str().unwrap());
+        assert!(std::fs::remove_file(filename).is_ok());
+
+        for _ in 0..PRINT_STATUS_POLLS {
+            assert_eq!(
+                send_packet(&mut printer, COMMAND_STATUS, false, &[]),
+                STATUS_PRINTING
+            );
+        }
+        assert_eq!(send_packet(&mut printer, COMMAND_STATUS, false, &[]), 0);
+
+        // Bad checksum
+        for byte in [0x88, 0x33, COMMAND_STATUS, 0, 0, 0, 0x00, 0x00, 0x00] {
+            printer.exchange(byte);
+        }
+        assert_eq!(printer.exchange(0x00), STATUS_CHECKSUM_ERROR);
+    }
+
+    #[test]
+    fn test_render_palette() {
+        // Color 3 in the first pixel, color 0 in the rest
+        let mut tiles = vec![0; BYTES_PER_TILE_ROW];
+        tiles[0] = 0x80;
+        tiles[1] = 0x80;
+        let rgb = render(&tiles, 0xe4);
+        assert_eq!(&rgb[0..6], &[0x00, 0x00, 0x00, 0xff, 0xff, 0xff]);
+        // Inverted
+        let rgb = render(&tiles, 0x1b);
+        assert_eq!(&rgb[0..6], &[0xff, 0xff, 0xff, 0x00, 0x00, 0x00]);
+    }
+}
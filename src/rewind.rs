@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+use crate::{error::Error, gameboy::Gameboy};
+
+/// History of save states, for going back in time.
+///
+/// A snapshot is taken every `interval` frames. Only the newest one is kept whole,
+/// each older one is stored as the difference to the one after it: the two states
+/// xor'ed together, which is mostly zeros, with the runs of zeros packed. When the
+/// history grows past the memory budget the oldest snapshots are dropped.
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    frames: u32,
+    newest: Option<Vec<u8>>,
+    // Oldest first, applying the last one to `newest` gives the snapshot before it
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// Call after every frame, takes a snapshot when it is time for it
+    pub fn record(&mut self, gameboy: &Gameboy) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(gameboy.save_state());
+        }
+    }
+
+    /// Goes back one snapshot. Returns false when there is nothing older to go back to.
+    pub fn step_back(&mut self, gameboy: &mut Gameboy) -> Result<bool, Error> {
+        self.frames = 0;
+        match self.pop() {
+            Some(state) => {
+                gameboy.load_state(&state)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Bytes used by the history
+    pub fn memory_used(&self) -> usize {
+        self.delta_bytes + self.newest.as_ref().map_or(0, |state| state.len())
+    }
+
+    /// Number of snapshots that can be gone back to
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            if newest.len() == state.len() {
+                let delta = compress(&xor(&newest, &state));
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // Can not be diffed, the older history is useless
+                self.deltas.clear();
+                self.delta_bytes = 0;
+            }
+        }
+        self.newest = Some(state);
+
+        while self.memory_used() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Gives back the newest snapshot and makes the one before it the newest.
+    // The oldest is never removed, so rewinding past the start of the history stays there.
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.as_mut()?;
+        let state = newest.clone();
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            *newest = xor(newest, &decompress(&delta, newest.len()));
+        }
+        Some(state)
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+// Pairs of (number of zeros, number of other bytes) followed by the other bytes
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|&&byte| byte == 0).count();
+        i += zeros;
+        let literals = data[i..].iter().take_while(|&&byte| byte != 0).count();
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literals);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+fn decompress(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = read_varint(data, &mut i);
+        let literals = read_varint(data, &mut i);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out.resize(len, 0);
+    out
+}
+
+// 7 bits at a time, the top bit is set when more bytes follow
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push((val as u8 & 0x7f) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        val |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut data = vec![0; 1000];
+        data[3] = 1;
+        data[4] = 2;
+        data[500] = 3;
+        let compressed = compress(&data);
+        assert!(compressed.len() < 20);
+        assert_eq!(decompress(&compressed, data.len()), data);
+    }
+
+    #[test]
+    fn test_goes_back_in_order() {
+        let mut rewind = Rewind::new(1, 1 << 20);
+        for i in 0..5u8 {
+            rewind.push(vec![i; 100]);
+        }
+        assert_eq!(rewind.len(), 5);
+        for i in (0..5u8).rev() {
+            assert_eq!(rewind.pop(), Some(vec![i; 100]));
+        }
+        // Stays at the oldest
+        assert_eq!(rewind.pop(), Some(vec![0; 100]));
+    }
+
+    #[test]
+    fn test_budget_drops_oldest() {
+        let mut rewind = Rewind::new(1, 1000);
+        for i in 0..100u8 {
+            let mut state = vec![0; 900];
+            state[0] = i;
+            rewind.push(state);
+        }
+        assert!(rewind.memory_used() <= 1000);
+        assert!(rewind.len() > 2);
+
+        let mut oldest = 0;
+        while rewind.len() > 1 {
+            oldest = rewind.pop().unwrap()[0];
+        }
+        assert!(oldest > 0);
+    }
+}
@@ -0,0 +1,210 @@
+use std::io::Write;
+
+use crate::{
+    error::Error,
+    interrupt::Interrupt,
+    link::Link,
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+// With the internal clock a bit is shifted every 512 cycles (8192 Hz)
+const CYCLES_PER_BIT: u32 = 512;
+
+/// The serial port, SB (0xff01) and SC (0xff02).
+///
+/// Writing 0x81 to SC starts a transfer with the internal clock: the 8 bits in SB are
+/// shifted out, most significant first, while the bits from the other side are shifted in.
+/// When all 8 are done bit 7 of SC is cleared and the serial interrupt is requested.
+/// With nothing connected the other side always sends 1s.
+///
+/// A transfer with the external clock waits for the other side to drive the clock,
+/// so without a cable it never finishes.
+pub struct Serial {
+    link: Option<Box<dyn Link>>,
+    // Cycles until we check if the other side has started a transfer
+    poll_cycles: u32,
+    sb: u8,
+    sc: u8,
+    // Bits left of the current internal clock transfer
+    bits_left: u8,
+    cycles: u32,
+    // The byte being shifted in from the other side
+    incoming: u8,
+    // Print each byte sent to stdout
+    console: bool,
+    interrupts: u8,
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial {
+            link: None,
+            poll_cycles: 0,
+            sb: 0,
+            sc: 0x7e,
+            bits_left: 0,
+            cycles: 0,
+            incoming: 0xff,
+            console: false,
+            interrupts: 0,
+        }
+    }
+}
+
+impl Serial {
+    /// Print every byte the game sends, test roms use this to report their results
+    pub fn set_console(&mut self, console: bool) {
+        self.console = console;
+    }
+
+    /// Connects a link cable
+    pub fn set_link(&mut self, link: Box<dyn Link>) {
+        self.link = Some(link);
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff01 => self.sb,
+            0xff02 => 0x7e | self.sc,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff01 => self.sb = val,
+            0xff02 => {
+                self.sc = val & 0x81;
+                if self.transfer_requested() && self.internal_clock() {
+                    self.start_transfer();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn transfer_requested(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 != 0
+    }
+
+    fn start_transfer(&mut self) {
+        if self.console {
+            print!("{}", self.sb as char);
+            std::io::stdout().flush().ok();
+        }
+        self.incoming = match &mut self.link {
+            Some(link) => link.exchange(self.sb),
+            None => 0xff,
+        };
+        self.bits_left = 8;
+        self.cycles = 0;
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if self.bits_left == 0 {
+            self.poll_link(cycles);
+            return;
+        }
+
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_BIT && self.bits_left > 0 {
+            self.cycles -= CYCLES_PER_BIT;
+            self.bits_left -= 1;
+            let bit = (self.incoming >> self.bits_left) & 1;
+            self.sb = (self.sb << 1) | bit;
+        }
+
+        if self.bits_left == 0 {
+            self.sc &= !0x80;
+            self.interrupts |= Interrupt::Serial.mask();
+        }
+    }
+
+    // The other side drives the clock. The whole byte is exchanged at once, checking
+    // about as often as a bit would be shifted.
+    fn poll_link(&mut self, cycles: u32) {
+        let link = match &mut self.link {
+            Some(link) => link,
+            None => return,
+        };
+        if self.poll_cycles > cycles {
+            self.poll_cycles -= cycles;
+            return;
+        }
+        self.poll_cycles = CYCLES_PER_BIT;
+
+        // The master gets what is in SB, but a slave only shifts in the byte while it
+        // has asked for a transfer
+        if let Some(byte) = link.poll(self.sb) {
+            if self.transfer_requested() && !self.internal_clock() {
+                self.sb = byte;
+                self.sc &= !0x80;
+                self.interrupts |= Interrupt::Serial.mask();
+            }
+        }
+    }
+
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::replace(&mut self.interrupts, 0)
+    }
+}
+
+// What is connected is not part of the state
+impl Snapshot for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.sb);
+        state.u8(self.sc);
+        state.u8(self.bits_left);
+        state.u32(self.cycles);
+        state.u8(self.incoming);
+        state.u32(self.poll_cycles);
+        state.u8(self.interrupts);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.sb = state.u8()?;
+        self.sc = state.u8()?;
+        self.bits_left = state.u8_at_most(8, "serial bits left")?;
+        self.cycles = state.u32()?;
+        self.incoming = state.u8()?;
+        self.poll_cycles = state.u32()?;
+        self.interrupts = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_internal_clock_transfer() {
+        let mut serial = Serial::default();
+        serial.write_register(0xff01, 0x41);
+        serial.write_register(0xff02, 0x81);
+        assert_eq!(serial.read_register(0xff02), 0xff);
+
+        serial.step(8 * CYCLES_PER_BIT - 4);
+        assert_eq!(serial.take_interrupts(), 0);
+        assert_eq!(serial.read_register(0xff02), 0xff);
+
+        serial.step(4);
+        assert_eq!(serial.take_interrupts(), Interrupt::Serial.mask());
+        assert_eq!(serial.read_register(0xff02), 0x7f);
+        // Nothing connected, so only 1s came in
+        assert_eq!(serial.read_register(0xff01), 0xff);
+    }
+
+    #[test]
+    fn test_external_clock_waits() {
+        let mut serial = Serial::default();
+        serial.write_register(0xff02, 0x80);
+        serial.step(100 * CYCLES_PER_BIT);
+        assert_eq!(serial.take_interrupts(), 0);
+        assert_eq!(serial.read_register(0xff02), 0xfe);
+    }
+}
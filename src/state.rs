@@ -0,0 +1,248 @@
+// Save states.
+//
+// A state file starts with the magic bytes, the format version and the checksum of the
+// rom it was made with, followed by each part of the machine written by its `Snapshot`
+// implementation. Everything is little endian.
+//
+// When the format changes, bump VERSION and use `StateReader::version` in `load_state`
+// to read the old layout, or drop support for it by raising MIN_VERSION.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+
+const MAGIC: &[u8; 8] = b"GBEMUSAV";
+pub const VERSION: u32 = 2;
+pub const MIN_VERSION: u32 = 1;
+
+/// Something that can be saved in and restored from a save state
+pub trait Snapshot {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Starts a new state with the header
+    pub fn new(rom_checksum: u16) -> StateWriter {
+        let mut state = StateWriter::default();
+        state.data.extend_from_slice(MAGIC);
+        state.u32(VERSION);
+        state.u16(rom_checksum);
+        state
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, val: f32) {
+        self.u32(val.to_bits());
+    }
+
+    /// Writes the length first, so a buffer of the wrong size is caught when loading
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.data.extend_from_slice(val);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    version: u32,
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header, the state must be made by a supported version for the same rom
+    pub fn new(data: &'a [u8], rom_checksum: u16) -> Result<StateReader<'a>, Error> {
+        if !data.starts_with(MAGIC) {
+            return Err(Error::InvalidSaveState("not a save state".to_string()));
+        }
+        let mut state = StateReader {
+            data,
+            pos: MAGIC.len(),
+            version: 0,
+        };
+
+        state.version = state.u32()?;
+        if state.version < MIN_VERSION || state.version > VERSION {
+            return Err(Error::UnsupportedSaveStateVersion(state.version));
+        }
+        if state.u16()? != rom_checksum {
+            return Err(Error::InvalidSaveState("made with another rom".to_string()));
+        }
+        Ok(state)
+    }
+
+    /// The format version the state was written with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Fails if there is anything left, which means the state does not match the machine
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.pos != self.data.len() {
+            return Err(Error::InvalidSaveState(
+                "unexpected data at the end".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() - self.pos < len {
+            return Err(Error::InvalidSaveState("ends too early".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// For values used as an index, a shift or a counter that must stay in range, so that a
+    /// broken state fails to load instead of crashing the emulator later
+    pub fn u8_at_most(&mut self, max: u8, name: &str) -> Result<u8, Error> {
+        let val = self.u8()?;
+        at_most(val, max, name)
+    }
+
+    pub fn u16_at_most(&mut self, max: u16, name: &str) -> Result<u16, Error> {
+        let val = self.u16()?;
+        at_most(val, max, name)
+    }
+
+    pub fn u32_at_most(&mut self, max: u32, name: &str) -> Result<u32, Error> {
+        let val = self.u32()?;
+        at_most(val, max, name)
+    }
+
+    /// Fills `buf`, which must have the same length as when it was saved
+    pub fn bytes(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let len = self.u32()? as usize;
+        if len != buf.len() {
+            return Err(Error::InvalidSaveState(format!(
+                "expected {} bytes, found {}",
+                buf.len(),
+                len
+            )));
+        }
+        buf.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+}
+
+fn at_most<T: PartialOrd + fmt::Display>(val: T, max: T, name: &str) -> Result<T, Error> {
+    if val > max {
+        return Err(Error::InvalidSaveState(format!(
+            "{} is {}, it can be at most {}",
+            name, val, max
+        )));
+    }
+    Ok(val)
+}
+
+/// Where a numbered save slot is stored, next to the rom: `game.gb` -> `game.ss1`
+pub fn slot_path(rom_file: &str, slot: u8) -> PathBuf {
+    Path::new(rom_file).with_extension(format!("ss{}", slot))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new(0x1234);
+        writer.u8(1);
+        writer.bool(true);
+        writer.u16(0xbeef);
+        writer.u64(1 << 40);
+        writer.f32(0.5);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data, 0x1234).unwrap();
+        assert_eq!(reader.version(), VERSION);
+        assert_eq!(reader.u8().unwrap(), 1);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u16().unwrap(), 0xbeef);
+        assert_eq!(reader.u64().unwrap(), 1 << 40);
+        assert_eq!(reader.f32().unwrap(), 0.5);
+        let mut buf = [0; 3];
+        reader.bytes(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_rejects_bad_states() {
+        let data = StateWriter::new(0x1234).into_bytes();
+        assert!(StateReader::new(&data, 0x4321).is_err());
+        assert!(StateReader::new(b"garbage", 0x1234).is_err());
+
+        let mut future = data.clone();
+        future[8] = VERSION as u8 + 1;
+        match StateReader::new(&future, 0x1234) {
+            Err(Error::UnsupportedSaveStateVersion(version)) => assert_eq!(version, VERSION + 1),
+            _ => panic!("expected an unsupported version"),
+        }
+
+        let mut reader = StateReader::new(&data, 0x1234).unwrap();
+        assert!(reader.u8().is_err());
+    }
+}
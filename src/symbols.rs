@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+
+/// An address in a rom bank. Addresses below 4000 and outside the rom are in bank 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BankedAddr {
+    pub bank: u16,
+    pub addr: u16,
+}
+
+impl BankedAddr {
+    /// `addr` as the cpu sees it with `rom_bank` mapped in at 4000 - 7fff
+    pub fn new(addr: u16, rom_bank: u16) -> BankedAddr {
+        let bank = if (0x4000..0x8000).contains(&addr) {
+            rom_bank
+        } else {
+            0
+        };
+        BankedAddr { bank, addr }
+    }
+}
+
+/// Labels from a symbol file, as written by RGBDS (`rgblink -n`) and read by no$gmb
+#[derive(Debug, Default)]
+pub struct Symbols {
+    names: BTreeMap<BankedAddr, String>,
+    addrs: HashMap<String, BankedAddr>,
+}
+
+impl Symbols {
+    /// Reads lines of `bank:addr label`, in hex. Everything after a `;` is a comment.
+    pub fn parse(src: &str) -> Result<Symbols, Error> {
+        let mut symbols = Symbols::default();
+
+        for (i, line) in src.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || Error::InvalidSymbolFile(format!("line {}: `{}`", i + 1, line));
+
+            let mut parts = line.split_whitespace();
+            let (addr, name) = match (parts.next(), parts.next()) {
+                (Some(addr), Some(name)) => (addr, name),
+                _ => return Err(invalid()),
+            };
+            let addr = parse_banked(addr).ok_or_else(invalid)?;
+            symbols.insert(addr, name);
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn load(path: &Path) -> Result<Symbols, Error> {
+        Symbols::parse(&fs::read_to_string(path)?)
+    }
+
+    /// The symbol file next to the rom, `game.gb` has `game.sym`
+    pub fn path_for_rom(rom_file: &str) -> PathBuf {
+        Path::new(rom_file).with_extension("sym")
+    }
+
+    /// The labels in the symbol file next to the rom, or none if there is no such file
+    pub fn for_rom(rom_file: &str) -> Result<Symbols, Error> {
+        let path = Symbols::path_for_rom(rom_file);
+        if path.exists() {
+            Symbols::load(&path)
+        } else {
+            Ok(Symbols::default())
+        }
+    }
+
+    fn insert(&mut self, addr: BankedAddr, name: &str) {
+        // With several labels at the same address the first one is shown
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+        self.addrs.insert(name.to_string(), addr);
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    /// The label at exactly this address
+    pub fn name(&self, addr: BankedAddr) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    /// The closest label at or before the address in the same bank and 16k region, and how
+    /// far after it the address is
+    pub fn nearest(&self, addr: BankedAddr) -> Option<(&str, u16)> {
+        let start = BankedAddr {
+            bank: addr.bank,
+            addr: addr.addr & 0xc000,
+        };
+        self.names
+            .range(start..=addr)
+            .next_back()
+            .map(|(label, name)| (name.as_str(), addr.addr - label.addr))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<BankedAddr> {
+        self.addrs.get(name).cloned()
+    }
+
+    /// The address as `label+offset`, or just in hex if there is no label before it
+    pub fn describe(&self, addr: BankedAddr) -> String {
+        match self.nearest(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("{:04x}", addr.addr),
+        }
+    }
+}
+
+// `bb:aaaa` in hex. Only the rom is banked, so RGBDS's WRAM and SRAM banks become bank 0.
+fn parse_banked(src: &str) -> Option<BankedAddr> {
+    let mut parts = src.splitn(2, ':');
+    let bank = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some(BankedAddr::new(addr, bank))
+}
+
+/// A location given by the user: a label, `bank:addr` or just `addr`, in hex. Without a bank
+/// it matches whatever rom bank is mapped in.
+pub fn parse_location(src: &str, symbols: &Symbols) -> Result<(Option<u16>, u16), Error> {
+    if let Some(addr) = symbols.lookup(src) {
+        return Ok((Some(addr.bank), addr.addr));
+    }
+    if let Some(addr) = parse_banked(src) {
+        return Ok((Some(addr.bank), addr.addr));
+    }
+    u16::from_str_radix(src, 16)
+        .map(|addr| (None, addr))
+        .map_err(|_| Error::UnknownSymbol(src.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink\n\
+                       00:0150 Main\n\
+                       00:0158 Main.loop\n\
+                       01:4000 Banked ; in the second bank\n\
+                       00:c000 wCounter\n\
+                       01:d000 wBuffer\n";
+
+    #[test]
+    fn test_names_and_offsets_by_bank() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.name(BankedAddr::new(0x0158, 1)), Some("Main.loop"));
+        assert_eq!(symbols.describe(BankedAddr::new(0x015b, 1)), "Main.loop+3");
+        assert_eq!(symbols.describe(BankedAddr::new(0x4002, 1)), "Banked+2");
+        assert_eq!(symbols.describe(BankedAddr::new(0x4002, 2)), "4002");
+        assert_eq!(symbols.describe(BankedAddr::new(0x0100, 1)), "0100");
+        assert_eq!(symbols.describe(BankedAddr::new(0xc000, 1)), "wCounter");
+        assert_eq!(symbols.describe(BankedAddr::new(0x8000, 1)), "8000");
+        assert_eq!(symbols.name(BankedAddr::new(0xd000, 1)), Some("wBuffer"));
+        assert_eq!(symbols.describe(BankedAddr::new(0xd002, 1)), "wBuffer+2");
+    }
+
+    #[test]
+    fn test_parses_locations() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(
+            parse_location("Main.loop", &symbols).unwrap(),
+            (Some(0), 0x0158)
+        );
+        assert_eq!(
+            parse_location("01:4010", &symbols).unwrap(),
+            (Some(1), 0x4010)
+        );
+        assert_eq!(parse_location("0150", &symbols).unwrap(), (None, 0x0150));
+        assert_eq!(
+            parse_location("wBuffer", &symbols).unwrap(),
+            (Some(0), 0xd000)
+        );
+        assert_eq!(
+            parse_location("01:d000", &symbols).unwrap(),
+            (Some(0), 0xd000)
+        );
+        assert!(parse_location("Nothing", &symbols).is_err());
+        assert!(Symbols::parse("00:0150").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    error::Error,
+    interrupt::Interrupt,
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+/// DIV, TIMA, TMA and TAC (0xff04 - 0xff07).
+///
+/// Everything is driven by a 16 bit counter that increases every cycle, DIV is the upper
+/// 8 bits of it. TIMA increases when the counter bit selected by TAC (and'ed with the
+/// enable bit) goes from 1 to 0. This is why writing to DIV or TAC can increase TIMA.
+#[derive(Default)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed in the last cycle. It reads as 0 until it is reloaded from TMA
+    // at the next cycle.
+    overflow: bool,
+    interrupts: u8,
+}
+
+impl Timer {
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff04 => (self.counter >> 8) as u8,
+            0xff05 => self.tima,
+            0xff06 => self.tma,
+            0xff07 => 0xf8 | self.tac,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff04 => {
+                let before = self.timer_bit();
+                self.counter = 0;
+                self.check_falling_edge(before);
+            }
+            0xff05 => {
+                // Writing to TIMA in the cycle after an overflow cancels the reload
+                self.overflow = false;
+                self.tima = val;
+            }
+            0xff06 => self.tma = val,
+            0xff07 => {
+                let before = self.timer_bit();
+                self.tac = val & 0x07;
+                self.check_falling_edge(before);
+            }
+            _ => (),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        // The timer is updated once per machine cycle (4 clocks)
+        for _ in 0..cycles / 4 {
+            if self.overflow {
+                self.overflow = false;
+                self.tima = self.tma;
+                self.interrupts |= Interrupt::Timer.mask();
+            }
+
+            let before = self.timer_bit();
+            self.counter = self.counter.wrapping_add(4);
+            self.check_falling_edge(before);
+        }
+    }
+
+    /// Interrupts requested since the last call
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::replace(&mut self.interrupts, 0)
+    }
+
+    // The counter bit selected by TAC, and'ed with the timer enable bit
+    fn timer_bit(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn check_falling_edge(&mut self, before: bool) {
+        if before && !self.timer_bit() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow = overflow;
+        }
+    }
+}
+
+impl Snapshot for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.bool(self.overflow);
+        state.u8(self.interrupts);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), Error> {
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()?;
+        self.overflow = state.bool()?;
+        self.interrupts = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tima_frequency() {
+        let mut timer = Timer::default();
+        // Enabled, increase every 16 cycles
+        timer.write_register(0xff07, 0x05);
+        timer.step(160);
+        assert_eq!(timer.read_register(0xff05), 10);
+    }
+
+    #[test]
+    fn test_overflow_reloads_after_one_cycle() {
+        let mut timer = Timer::default();
+        timer.write_register(0xff06, 0xab);
+        timer.write_register(0xff05, 0xff);
+        timer.write_register(0xff07, 0x05);
+
+        timer.step(16);
+        assert_eq!(timer.read_register(0xff05), 0x00);
+        assert_eq!(timer.take_interrupts(), 0);
+
+        timer.step(4);
+        assert_eq!(timer.read_register(0xff05), 0xab);
+        assert_eq!(timer.take_interrupts(), Interrupt::Timer.mask());
+    }
+
+    #[test]
+    fn test_div_write_glitch() {
+        let mut timer = Timer::default();
+        timer.write_register(0xff07, 0x05);
+        // Bit 3 of the counter is now set
+        timer.step(8);
+        assert_eq!(timer.read_register(0xff05), 0);
+
+        timer.write_register(0xff04, 0x12);
+        assert_eq!(timer.read_register(0xff04), 0);
+        assert_eq!(timer.read_register(0xff05), 1);
+    }
+
+    #[test]
+    fn test_tac_write_glitch() {
+        let mut timer = Timer::default();
+        timer.write_register(0xff07, 0x05);
+        timer.step(8);
+
+        // Disabling the timer while the selected bit is set increases TIMA
+        timer.write_register(0xff07, 0x01);
+        assert_eq!(timer.read_register(0xff05), 1);
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::str::FromStr;
+
+use crate::{
+    cpu::Flow,
+    error::Error,
+    gameboy::{Gameboy, CYCLES_PER_FRAME},
+    instructions::Instruction,
+    mem::ReadMem,
+    symbols::Symbols,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // The format of gameboy-doctor, to compare with traces of emulators we know are right
+    Doctor,
+    // With the cycle count and the instruction, for reading
+    Rich,
+}
+
+impl FromStr for TraceFormat {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<TraceFormat, Error> {
+        match name.to_lowercase().as_ref() {
+            "doctor" => Ok(TraceFormat::Doctor),
+            "rich" => Ok(TraceFormat::Rich),
+            _ => Err(Error::UnknownTraceFormat(name.to_string())),
+        }
+    }
+}
+
+/// Writes a line for every instruction, with the state of the cpu before it runs
+pub struct TraceWriter {
+    out: BufWriter<Box<dyn Write + Send>>,
+    format: TraceFormat,
+    symbols: Symbols,
+}
+
+impl TraceWriter {
+    pub fn create(filename: &str, format: TraceFormat) -> Result<TraceWriter, Error> {
+        Ok(TraceWriter::new(Box::new(File::create(filename)?), format))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>, format: TraceFormat) -> TraceWriter {
+        TraceWriter {
+            out: BufWriter::new(out),
+            format,
+            symbols: Symbols::default(),
+        }
+    }
+
+    /// Labels for the instructions in the rich format
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Logs the instruction the gameboy is about to run. Jumps to interrupt handlers are
+    /// not instructions, so they are not logged.
+    pub fn write(&mut self, gameboy: &Gameboy) -> Result<(), Error> {
+        let cpu = &gameboy.cpu;
+        let mmu = &gameboy.mmu;
+        if let Flow::Interrupt { .. } = cpu.next_flow(mmu)? {
+            return Ok(());
+        }
+
+        match self.format {
+            TraceFormat::Doctor => writeln!(self.out, "{}", doctor_line(gameboy)?)?,
+            TraceFormat::Rich => {
+                let inst = disassemble(gameboy, &self.symbols);
+                let flag = |set: bool, name: char| if set { name } else { '-' };
+                writeln!(
+                    self.out,
+                    "{:>12} {:04x}  {:<20} A:{:02x} F:{}{}{}{} BC:{:04x} DE:{:04x} HL:{:04x} \
+                     SP:{:04x}",
+                    gameboy.cycles(),
+                    cpu.pc,
+                    inst,
+                    cpu.a,
+                    flag(cpu.flags.zero(), 'Z'),
+                    flag(cpu.flags.subtract(), 'N'),
+                    flag(cpu.flags.half_carry(), 'H'),
+                    flag(cpu.flags.carry(), 'C'),
+                    cpu.get_bc(),
+                    cpu.get_de(),
+                    cpu.get_hl(),
+                    cpu.sp
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// The next instruction, or why it could not be read
+fn disassemble(gameboy: &Gameboy, symbols: &Symbols) -> String {
+    let pc = gameboy.cpu.pc;
+    match Instruction::parse(pc, &gameboy.mmu.unrecorded()) {
+        Ok((inst, _)) => inst
+            .with_symbols(pc, symbols, gameboy.mmu.rom_bank())
+            .to_string(),
+        Err(err) => err.to_string(),
+    }
+}
+
+/// The state of the cpu in the gameboy-doctor format
+pub fn doctor_line(gameboy: &Gameboy) -> Result<String, Error> {
+    let cpu = &gameboy.cpu;
+    let mem = gameboy.mmu.unrecorded();
+    let pcmem = (0..4)
+        .map(|i| {
+            let byte = mem.read_u8(cpu.pc.wrapping_add(i))?;
+            Ok(format!("{:02X}", byte))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+         SP:{:04X} PC:{:04X} PCMEM:{}",
+        cpu.a,
+        cpu.flags.to_u8(),
+        cpu.b,
+        cpu.c,
+        cpu.d,
+        cpu.e,
+        cpu.h,
+        cpu.l,
+        cpu.sp,
+        cpu.pc,
+        pcmem.join(",")
+    ))
+}
+
+// The `name:value` fields of a doctor line, in order
+fn fields(line: &str) -> Vec<(&str, &str)> {
+    line.split_whitespace()
+        .filter_map(|field| {
+            let mut parts = field.splitn(2, ':');
+            Some((parts.next()?, parts.next()?))
+        })
+        .collect()
+}
+
+/// Where our trace first differs from the reference
+#[derive(Debug)]
+pub struct Divergence {
+    // Line number in the reference, from 1
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    // The names of the fields that differ
+    pub fields: Vec<String>,
+    // The lines before it, with the instruction that ran from each
+    pub context: Vec<(String, String)>,
+}
+
+/// Runs the gameboy alongside a reference trace in the doctor format, and stops at the first
+/// line that differs. Fields missing from the reference are not compared, and case does not
+/// matter. When the reference starts after the boot rom we run until its first pc before
+/// comparing. The instructions in the context are shown with `symbols`.
+pub fn diff<R: BufRead>(
+    gameboy: &mut Gameboy,
+    reference: R,
+    context: usize,
+    symbols: &Symbols,
+) -> Result<Option<Divergence>, Error> {
+    let mut history: VecDeque<(String, String)> = VecDeque::with_capacity(context + 1);
+    let mut started = false;
+
+    for (i, expected) in reference.lines().enumerate() {
+        let expected = expected?;
+        if expected.trim().is_empty() {
+            continue;
+        }
+
+        if !started {
+            let pc = fields(&expected)
+                .into_iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("PC"))
+                .and_then(|(_, pc)| u16::from_str_radix(pc, 16).ok())
+                .ok_or_else(|| {
+                    Error::InvalidTrace(format!("line {} has no PC: `{}`", i + 1, expected))
+                })?;
+            run_to(gameboy, pc)?;
+            started = true;
+        }
+
+        // Jumps to interrupt handlers are not in the trace
+        while let Flow::Interrupt { .. } = gameboy.cpu.next_flow(&gameboy.mmu)? {
+            gameboy.step()?;
+        }
+
+        let actual = doctor_line(gameboy)?;
+        let actual_fields = fields(&actual);
+        let differing: Vec<String> = fields(&expected)
+            .into_iter()
+            .filter(|(name, value)| {
+                !actual_fields
+                    .iter()
+                    .any(|(n, v)| n.eq_ignore_ascii_case(name) && v.eq_ignore_ascii_case(value))
+            })
+            .map(|(name, _)| name.to_string())
+            .collect();
+        if !differing.is_empty() {
+            return Ok(Some(Divergence {
+                line: i + 1,
+                expected,
+                actual,
+                fields: differing,
+                context: history.into_iter().collect(),
+            }));
+        }
+
+        let inst = disassemble(gameboy, symbols);
+        if history.len() == context {
+            history.pop_front();
+        }
+        if context > 0 {
+            history.push_back((actual, inst));
+        }
+        gameboy.step()?;
+    }
+
+    Ok(None)
+}
+
+// The boot rom takes a bit over 2 seconds, so this is plenty
+const MAX_FRAMES_TO_START: u64 = 600;
+
+fn run_to(gameboy: &mut Gameboy, pc: u16) -> Result<(), Error> {
+    let limit = gameboy.cycles() + MAX_FRAMES_TO_START * CYCLES_PER_FRAME as u64;
+    while gameboy.cpu.pc != pc {
+        if gameboy.cycles() > limit {
+            return Err(Error::InvalidTrace(format!(
+                "never got to the first pc of the reference, {:04x}",
+                pc
+            )));
+        }
+        gameboy.step()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cpu::Cpu, mem::Mmu};
+    use std::sync::{Arc, Mutex};
+
+    // Lets the test read what was written after the writer has taken ownership
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_doctor_and_rich_lines() {
+        // inc a; ret z
+        let mut gameboy = Gameboy::new(Mmu::with_mem(vec![0x3c, 0xc8, 0x00, 0x00]), Cpu::default());
+        gameboy.cpu.sp = 0xfffe;
+
+        let out = Shared::default();
+        let mut doctor = TraceWriter::new(Box::new(out.clone()), TraceFormat::Doctor);
+        let mut rich = TraceWriter::new(Box::new(out.clone()), TraceFormat::Rich);
+        doctor.write(&gameboy).unwrap();
+        gameboy.step().unwrap();
+        rich.write(&gameboy).unwrap();
+        doctor.flush().unwrap();
+        rich.flush().unwrap();
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0000 PCMEM:3C,C8,00,00"
+        );
+        assert_eq!(
+            lines[1],
+            "           4 0001  RET Z                A:01 F:---- BC:0000 DE:0000 HL:0000 SP:fffe"
+        );
+    }
+
+    #[test]
+    fn test_diff_stops_at_the_first_difference() {
+        // inc a; jr -3
+        let rom = vec![0x3c, 0x18, 0xfd, 0x00];
+        let reference = "A:00 PC:0000\nA:01 PC:0001\n\nA:01 PC:0000\nA:03 F:00 PC:0001\n";
+
+        let mut gameboy = Gameboy::new(Mmu::with_mem(rom.clone()), Cpu::default());
+        let divergence = diff(&mut gameboy, reference.as_bytes(), 2, &Symbols::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.line, 5);
+        assert_eq!(divergence.fields, vec!["A"]);
+        assert_eq!(divergence.context.len(), 2);
+        assert_eq!(divergence.context[1].1, "INC A");
+
+        let mut gameboy = Gameboy::new(Mmu::with_mem(rom), Cpu::default());
+        let matching = "a:00 pc:0000\na:01 pc:0001\n";
+        assert!(
+            diff(&mut gameboy, matching.as_bytes(), 2, &Symbols::default())
+                .unwrap()
+                .is_none()
+        );
+    }
+}